@@ -6,7 +6,10 @@ use serde::Serialize;
 use std::{collections::HashMap, path::Path};
 use tabled::{Table, builder::Builder};
 
+use crate::config::CustomItem;
+use crate::ignore::IgnoreRules;
 use crate::mpd_client::MPDClient;
+use crate::mpd_response::Response;
 
 #[derive(Debug, Clone)]
 // for clap
@@ -38,14 +41,16 @@ pub(crate) fn collection_information(
     client: &mut MPDClient,
     music_dir: &Path,
     format: CollectionFormat,
+    custom_items: &[CustomItem],
+    ignore: &IgnoreRules,
 ) -> String {
-    let (mut tracks, albums) = build_collection_maps(client, music_dir);
+    let (mut tracks, albums) = build_collection_maps(client, music_dir, ignore);
 
     match format {
         CollectionFormat::Summary => build_summary_table(tracks, albums).to_string(),
         CollectionFormat::Rofi => {
             tracks.extend(albums);
-            add_custom_items(client, music_dir, &mut tracks);
+            add_custom_items(client, music_dir, custom_items, &mut tracks);
 
             let rofi_strs: Vec<String> = tracks
                 .iter()
@@ -58,7 +63,7 @@ pub(crate) fn collection_information(
         }
         CollectionFormat::Json => {
             tracks.extend(albums);
-            add_custom_items(client, music_dir, &mut tracks);
+            add_custom_items(client, music_dir, custom_items, &mut tracks);
             serde_json::to_string(&tracks).unwrap()
         }
         CollectionFormat::Fixmes => {
@@ -80,6 +85,7 @@ pub(crate) fn collection_information(
 pub(crate) fn build_collection_maps(
     client: &mut MPDClient,
     music_dir: &Path,
+    ignore: &IgnoreRules,
 ) -> (HashMap<String, IndexedItem>, HashMap<String, IndexedItem>) {
     // 1. find all flac, add to dict
     // 2. find everything else, add to dict
@@ -87,14 +93,19 @@ pub(crate) fn build_collection_maps(
     // necessary
     let all_flac = client
         .send_command("find \"(file contains \'.flac\')\" sort AlbumSort\n".to_string())
-        .unwrap();
+        .expect("MPD could not list flac tracks");
     let remainder = client
         .send_command("find \"(!(file contains \'.flac\'))\" sort AlbumSort\n".to_string())
-        .unwrap();
+        .expect("MPD could not list non-flac tracks");
 
-    let (flac_tracks, flac_albums) =
-        parse_info(all_flac.trim().split("file:").collect(), music_dir);
-    let (mut tracks, mut albums) = parse_info(remainder.trim().split("file:").collect(), music_dir);
+    let not_ignored = |records: Vec<Response>| -> Vec<Response> {
+        records
+            .into_iter()
+            .filter(|song| !ignore.ignores_song(song))
+            .collect()
+    };
+    let (flac_tracks, flac_albums) = parse_info(not_ignored(all_flac.records("file")), music_dir);
+    let (mut tracks, mut albums) = parse_info(not_ignored(remainder.records("file")), music_dir);
 
     // NOTE: since extend overwrites existing keys with new values, we extend the lower quality
     // dict to upsert to the better quality tracks
@@ -107,22 +118,19 @@ pub(crate) fn build_collection_maps(
 fn add_custom_items(
     client: &mut MPDClient,
     music_dir: &Path,
+    custom_items: &[CustomItem],
     tracks: &mut HashMap<String, IndexedItem>,
 ) {
     // NOTE: assumes playlist naming scheme with hyphen seperators
     let playlists: HashMap<String, IndexedItem> = client
         .send_command("listplaylists\n".to_string())
-        .unwrap()
-        .split("\n")
-        // PERF: is this inefficient/brittle and I should just skip every other element? Yes.
-        // Does it work for me to get this to a place I can use it? Also yes.
-        .filter(|s| !s.contains("Last-Modified"))
-        .filter(|s| !s.is_empty())
+        .expect("MPD could not list playlists")
+        .get_all("playlist")
         .map(|s| {
             (
                 s.replace("-", " ").to_uppercase(),
                 IndexedItem {
-                    path: "mpc load ".to_string() + s.trim_start_matches("playlist: "),
+                    path: "mpc load ".to_string() + s,
                     cover_path: Some(
                         music_dir
                             .join("playlist-icon.png")
@@ -139,76 +147,32 @@ fn add_custom_items(
         .collect();
     tracks.extend(playlists);
 
-    // I used the eurydice to run the eurydice
-    tracks.insert(
-        "EURYDICE: 3 Random Albums".to_string(),
-        IndexedItem {
-            path: "eurydice surprise-me album --count 3".to_string(),
-            cover_path: Some(music_dir.join("eurydice.png").to_str().unwrap().to_string()),
-            item_type: IndexedItemType::Playlist,
-            title: "Eurydice: 3 Random Albums".to_string(),
-            artist: "Eurydice".to_string(),
-        },
-    );
-
-    tracks.insert(
-        "EURYDICE: Random Album".to_string(),
-        IndexedItem {
-            path: "eurydice surprise-me album".to_string(),
-            cover_path: Some(music_dir.join("eurydice.png").to_str().unwrap().to_string()),
-            item_type: IndexedItemType::Playlist,
-            title: "Eurydice: Random Album".to_string(),
-            artist: "Eurydice".to_string(),
-        },
-    );
-    tracks.insert(
-        "EURYDICE: Mixtape (1 Hour)".to_string(),
-        IndexedItem {
-            path: "eurydice surprise-me playlist".to_string(),
-            cover_path: Some(music_dir.join("eurydice.png").to_str().unwrap().to_string()),
-            item_type: IndexedItemType::Playlist,
-            title: "Eurydice: Mixtape (1 Hour)".to_string(),
-            artist: "Eurydice".to_string(),
-        },
-    );
-    tracks.insert(
-        "EURYDICE: Mixtape (3 Hours)".to_string(),
-        IndexedItem {
-            path: "eurydice surprise-me playlist --target-length 180".to_string(),
-            cover_path: Some(music_dir.join("eurydice.png").to_str().unwrap().to_string()),
-            item_type: IndexedItemType::Playlist,
-            title: "Eurydice: Mixtape (3 Hours)".to_string(),
-            artist: "Eurydice".to_string(),
-        },
-    );
+    for item in custom_items {
+        tracks.insert(
+            item.name.clone(),
+            IndexedItem {
+                path: item.command.clone(),
+                cover_path: item
+                    .icon
+                    .as_ref()
+                    .map(|icon| music_dir.join(icon).to_str().unwrap().to_string()),
+                item_type: IndexedItemType::Playlist,
+                title: item.title.clone(),
+                artist: "Eurydice".to_string(),
+            },
+        );
+    }
 }
 
 fn parse_info(
-    all_track_details: Vec<&str>,
+    all_track_details: Vec<Response>,
     music_dir: &Path,
 ) -> (HashMap<String, IndexedItem>, HashMap<String, IndexedItem>) {
     let mut tracks = HashMap::<String, IndexedItem>::new();
     let mut albums = HashMap::<String, IndexedItem>::new();
 
-    all_track_details.iter().for_each(|ti| {
-        // FIXME: this little thing to prepend the key back on (which I need for the dictionary
-        // below) can definitely be done better, there's a way to maintain it during the split
-        // operation I'm sure. Can't use split_inclusive.
-        let corrected = "file:".to_string() + ti;
-        let track_info: HashMap<String, String> = corrected
-            .trim()
-            .split('\n')
-            .map(|l| {
-                l.split_once(':')
-                    .map(|(a, b)| (a.trim().to_string(), b.trim().to_string()))
-                    .unwrap_or_else(|| {
-                        warn!("Failed to parse track info from {ti}");
-                        ("".to_string(), "".to_string())
-                    })
-            })
-            .collect();
-
-        let unknown = "Unknown".to_string();
+    all_track_details.iter().for_each(|track_info| {
+        let unknown = "Unknown";
 
         // NOTE: have to handle both Artist and AlbumArtist tags here. Some tracks have one or
         // the other, some have both. Take the first one we find.
@@ -217,7 +181,7 @@ fn parse_info(
         let artist = match (track_info.get("AlbumArtist"), track_info.get("Artist")) {
             (Some(a), _) => a,
             (_, Some(a)) => a,
-            (None, None) => &unknown
+            (None, None) => unknown
         };
 
         track_key += artist;
@@ -230,14 +194,14 @@ fn parse_info(
 
         // TODO: this does some weird stuff with singles where they are indexed as both a track
         // (ok) and an "Unknown" album. Not hugely annoying right now but to be aware of.
-        let track_title = track_info.get("Title").unwrap_or(&unknown);
-        let album_title = track_info.get("Album").unwrap_or(&unknown);
+        let track_title = track_info.get("Title").unwrap_or(unknown);
+        let album_title = track_info.get("Album").unwrap_or(unknown);
         track_key += track_title;
         album_key += album_title;
 
         let file_path = track_info.get("file").unwrap_or_else(|| {
             warn!("No file path found for {track_key}");
-            &unknown
+            unknown
         });
 
         // FIXME: unwraps
@@ -0,0 +1,234 @@
+use log::debug;
+use serde::{Deserialize, Serialize};
+use std::{env, fs, path::PathBuf};
+
+use crate::daemon::PlayThreshold;
+use crate::sampling::Weighting;
+use crate::stats::StatsFormat;
+use crate::surprise_me::Age;
+
+/// Everything that can be set in `config.toml`. Every key is optional, anything left out falls
+/// back to the defaults here, and command line flags win over both.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct Config {
+    pub(crate) connection: ConnectionConfig,
+    pub(crate) storage: StorageConfig,
+    pub(crate) daemon: DaemonConfig,
+    pub(crate) surprise_me: SurpriseMeConfig,
+    pub(crate) collection: CollectionConfig,
+    pub(crate) stats: StatsConfig,
+    pub(crate) ignore: IgnoreConfig,
+    pub(crate) listenbrainz: ListenBrainzConfig,
+}
+
+/// Where MPD is. `MPD_HOST` and `MPD_PORT` still take precedence, the same as for `mpc`.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct ConnectionConfig {
+    /// Same forms as `MPD_HOST`: a socket path, `@abstract`, or a hostname
+    pub(crate) host: Option<String>,
+    pub(crate) port: Option<u16>,
+    pub(crate) password: Option<String>,
+    /// Use this instead of asking MPD, which only answers over a local socket
+    pub(crate) music_directory: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct StorageConfig {
+    /// Defaults to `$XDG_DATA_HOME/eurydice/db.db3`
+    pub(crate) database: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct DaemonConfig {
+    pub(crate) play_fraction: f64,
+    pub(crate) play_seconds: f64,
+    /// Keep the `playCount` and `lastPlayed` stickers up to date as plays are recorded
+    pub(crate) write_stickers: bool,
+}
+
+impl Default for DaemonConfig {
+    fn default() -> Self {
+        DaemonConfig {
+            play_fraction: 0.5,
+            play_seconds: 240.0,
+            write_stickers: true,
+        }
+    }
+}
+
+impl DaemonConfig {
+    fn check(&self) -> Result<(), String> {
+        PlayThreshold::check_fraction(self.play_fraction)
+            .and(PlayThreshold::check_seconds(self.play_seconds))
+            .map(|_| ())
+            .map_err(|err| format!("[daemon] {err}"))
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct SurpriseMeConfig {
+    /// Minutes
+    pub(crate) target_length: f32,
+    /// Minutes either side of the target length that a playlist may land on
+    pub(crate) length_tolerance: f32,
+    pub(crate) album_count: u16,
+    /// How many less-played tracks to shuffle and pick a playlist from
+    pub(crate) candidate_limit: u32,
+    pub(crate) weighting: Weighting,
+    /// Leave out anything played more recently than this, e.g. `30d`
+    pub(crate) not_played_within: Option<Age>,
+}
+
+impl Default for SurpriseMeConfig {
+    fn default() -> Self {
+        SurpriseMeConfig {
+            target_length: 60.0,
+            length_tolerance: 2.0,
+            album_count: 1,
+            candidate_limit: 300,
+            weighting: Weighting::RecencyDecay,
+            not_played_within: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct CollectionConfig {
+    /// Extra entries for the rofi and json listings, after the MPD playlists
+    pub(crate) custom_items: Vec<CustomItem>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct CustomItem {
+    /// What rofi shows and what the json is keyed by
+    pub(crate) name: String,
+    pub(crate) title: String,
+    pub(crate) command: String,
+    /// Relative to the music directory, unless absolute
+    pub(crate) icon: Option<PathBuf>,
+}
+
+impl Default for CollectionConfig {
+    fn default() -> Self {
+        // I used the eurydice to run the eurydice
+        let eurydice_item = |name: &str, command: &str| CustomItem {
+            name: "EURYDICE: ".to_string() + name,
+            title: "Eurydice: ".to_string() + name,
+            command: command.to_string(),
+            icon: Some(PathBuf::from("eurydice.png")),
+        };
+        CollectionConfig {
+            custom_items: vec![
+                eurydice_item("3 Random Albums", "eurydice surprise-me album --count 3"),
+                eurydice_item("Random Album", "eurydice surprise-me album"),
+                eurydice_item("Mixtape (1 Hour)", "eurydice surprise-me playlist"),
+                eurydice_item(
+                    "Mixtape (3 Hours)",
+                    "eurydice surprise-me playlist --target-length 180",
+                ),
+            ],
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct StatsConfig {
+    pub(crate) format: StatsFormat,
+    /// How many entries the top tracks/albums/artists lists hold
+    pub(crate) top_limit: u32,
+}
+
+impl Default for StatsConfig {
+    fn default() -> Self {
+        StatsConfig {
+            format: StatsFormat::Table,
+            top_limit: 5,
+        }
+    }
+}
+
+/// Tracks that are never recorded, suggested or listed.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct IgnoreConfig {
+    /// Globs against the path relative to the music directory, e.g. `Audiobooks/**`
+    pub(crate) paths: Vec<String>,
+    pub(crate) genres: Vec<String>,
+    pub(crate) artists: Vec<String>,
+    /// Seconds, anything shorter is ignored
+    pub(crate) min_duration: Option<f64>,
+}
+
+/// Where the daemon submits listens to. Nothing is submitted without a token.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct ListenBrainzConfig {
+    /// The API root of ListenBrainz or anything compatible with it
+    pub(crate) url: String,
+    pub(crate) token: Option<String>,
+}
+
+impl Default for ListenBrainzConfig {
+    fn default() -> Self {
+        ListenBrainzConfig {
+            url: "https://api.listenbrainz.org".to_string(),
+            token: None,
+        }
+    }
+}
+
+fn default_path() -> PathBuf {
+    PathBuf::from(
+        env::var("XDG_CONFIG_HOME")
+            .unwrap_or(env::var("HOME").expect("Home env var not set") + "/.config")
+            + "/eurydice/config.toml",
+    )
+}
+
+/// Read the config file given with `--config`, which has to exist, or else the default one, or
+/// the defaults if there isn't one.
+pub(crate) fn load(path: Option<&PathBuf>) -> Result<Config, String> {
+    let explicit = path.is_some();
+    let path = path.cloned().unwrap_or_else(default_path);
+    match fs::read_to_string(&path) {
+        Ok(contents) => toml::from_str(&contents)
+            .and_then(|config: Config| {
+                config.daemon.check().map_err(serde::de::Error::custom)?;
+                Ok(config)
+            })
+            .map_err(|err| format!("{}: {err}", path.display())),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound && !explicit => {
+            debug!("No config at {}, using defaults", path.display());
+            Ok(Config::default())
+        }
+        Err(err) => Err(format!("{}: {err}", path.display())),
+    }
+}
+
+impl Config {
+    /// The effective config as TOML, with the password hidden.
+    pub(crate) fn show(&self) -> String {
+        let mut shown = self.clone();
+        if shown.connection.password.is_some() {
+            shown.connection.password = Some("********".to_string());
+        }
+        if shown.listenbrainz.token.is_some() {
+            shown.listenbrainz.token = Some("********".to_string());
+        }
+        let mut out = toml::to_string_pretty(&shown).expect("Config is always valid TOML");
+        for var in ["MPD_HOST", "MPD_PORT"] {
+            if env::var(var).is_ok() {
+                out = format!("# NOTE: {var} is set and overrides [connection]\n") + &out;
+            }
+        }
+        out
+    }
+}
@@ -1,89 +1,385 @@
+use crate::ignore::IgnoreRules;
+use crate::listenbrainz::Submitter;
 use crate::mpd_client::MPDClient;
-use log::{debug, warn};
+use crate::mpd_response::{CommandError, Response};
+use crate::stickers;
+use crate::tracks::{TrackInfo, upsert_track};
+use log::{debug, error, info, trace, warn};
 use rusqlite::Connection;
-use std::collections::HashMap;
 use std::path::Path;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
-pub(crate) fn handle_song_change(
-    new_song: String,
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+/// How much of a song has to actually be heard before it counts as a play. Whichever of the
+/// fraction of the song or the flat number of seconds comes first wins, so the defaults match the
+/// Last.fm convention of half the track or four minutes.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct PlayThreshold {
+    pub(crate) fraction: f64,
+    pub(crate) max_seconds: f64,
+}
+
+impl PlayThreshold {
+    /// The whole track can never count: listening time runs a little short of the duration, and
+    /// MPD moves on to the next song before the timer would fire, so it ends up as a skip.
+    pub(crate) fn check_fraction(fraction: f64) -> Result<f64, String> {
+        if fraction > 0.0 && fraction < 1.0 {
+            Ok(fraction)
+        } else {
+            Err(format!(
+                "play fraction must be above 0 and below 1, not {fraction}"
+            ))
+        }
+    }
+
+    pub(crate) fn check_seconds(seconds: f64) -> Result<f64, String> {
+        if seconds.is_finite() && seconds > 0.0 {
+            Ok(seconds)
+        } else {
+            Err(format!(
+                "play seconds must be a positive number, not {seconds}"
+            ))
+        }
+    }
+
+    fn for_duration(&self, duration: Option<f64>) -> Duration {
+        let seconds = match duration {
+            Some(duration) => (duration * self.fraction).min(self.max_seconds),
+            // streams and the like have no duration, so only the flat limit applies
+            None => self.max_seconds,
+        };
+        Duration::from_secs_f64(seconds.max(0.0))
+    }
+}
+
+pub(crate) fn parse_play_fraction(s: &str) -> Result<f64, String> {
+    s.parse()
+        .map_err(|err| format!("{err}"))
+        .and_then(PlayThreshold::check_fraction)
+}
+
+pub(crate) fn parse_play_seconds(s: &str) -> Result<f64, String> {
+    s.parse()
+        .map_err(|err| format!("{err}"))
+        .and_then(PlayThreshold::check_seconds)
+}
+
+/// The song MPD is currently on and how much of it we have heard so far. Listening time is
+/// accumulated from the wall clock while the player is in the `play` state, so pausing stops the
+/// count and seeking neither adds nor removes anything.
+#[derive(Debug)]
+struct NowPlaying {
+    song: Response,
+    started_at: SystemTime,
+    listened: Duration,
+    playing_since: Option<Instant>,
+    last_elapsed: f64,
+    threshold: Duration,
+    recorded: bool,
+    /// Matched an ignore rule, so is never recorded at all
+    ignored: bool,
+}
+
+impl NowPlaying {
+    fn new(song: Response, threshold: Duration, ignored: bool) -> NowPlaying {
+        NowPlaying {
+            song,
+            started_at: SystemTime::now(),
+            listened: Duration::ZERO,
+            playing_since: None,
+            last_elapsed: 0.0,
+            threshold,
+            recorded: false,
+            ignored,
+        }
+    }
+
+    /// Where in the song the player is now, extrapolated from the last status update.
+    fn position(&self) -> f64 {
+        self.last_elapsed
+            + self
+                .playing_since
+                .map(|since| since.elapsed().as_secs_f64())
+                .unwrap_or(0.0)
+    }
+
+    /// Bank any listening time since the last update and stop the clock.
+    fn pause(&mut self) {
+        if let Some(since) = self.playing_since.take() {
+            self.listened += since.elapsed();
+        }
+    }
+
+    fn remaining(&self) -> Duration {
+        self.threshold.saturating_sub(self.listened)
+    }
+}
+
+/// What happened to a track, as stored in the `event` column of `history`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum HistoryEvent {
+    /// Heard past the play threshold.
+    Play,
+    /// Moved away from before the play threshold, at this many seconds in.
+    Skip { position: f64 },
+}
+
+impl HistoryEvent {
+    fn name(&self) -> &'static str {
+        match self {
+            HistoryEvent::Play => "play",
+            HistoryEvent::Skip { .. } => "skip",
+        }
+    }
+}
+
+pub(crate) fn record_event(
+    song: &Response,
+    event: HistoryEvent,
+    started_at: SystemTime,
+    client: &mut MPDClient,
     db: &Connection,
     music_dir: &Path,
 ) -> Result<(), rusqlite::Error> {
-    debug!("Handling song change to {new_song}");
-    let track_info: HashMap<String, String> = new_song
-        .trim()
-        .split('\n')
-        .map(|l| {
-            l.split_once(':')
-                .map(|(a, b)| (a.trim().to_string(), b.trim().to_string()))
-                .unwrap_or_else(|| {
-                    warn!("Failed to parse new track info from {new_song}");
-                    ("".to_string(), "".to_string())
-                })
-        })
-        .collect();
+    debug!("Recording {event:?} of {song:?}");
 
     // queue is empty, we can just break
-    if track_info.is_empty() {
-        debug!("Queue is empty, short-circuiting before DB write");
+    let Some(track) = TrackInfo::from_song(song) else {
+        debug!("No track to record, short-circuiting before DB write");
         return Ok(());
-    }
+    };
 
-    let mut song_change = db.prepare(
-        "
-        INSERT INTO tracks(title,artist,album,lengthseconds,playcount,path)
-        VALUES (?1, ?2, ?3, ?4, 1, ?5)
-        ON CONFLICT(title,artist,album) DO UPDATE SET playcount=playcount+1
-        RETURNING id",
-    )?;
+    // NOTE: skips still need a tracks row to point at, they just don't count towards playcount
+    let plays = (event == HistoryEvent::Play) as i64;
+    // NOTE: if MPD can't say, the other file is assumed to still be there rather than taken over
+    let track_id = upsert_track(db, &track, music_dir, plays, &mut |file| {
+        client.has_song(file).unwrap_or(true)
+    })?;
+    debug!("Track count update stored successfully");
 
-    let full_path = music_dir
-        .join(&track_info["file"])
-        .to_str()
-        .unwrap()
-        .to_string();
-    song_change.query_one(
-        [
-            &track_info["Title"],
-            &track_info["Artist"],
-            track_info
-                .get("Album")
-                .unwrap_or(&"Unknown Album".to_string()),
-            &track_info["duration"],
-            &full_path,
-        ],
-        |id| {
-            debug!("Track count update stored successfully");
-            let retid: i32 = id.get(0)?;
-            db.execute("INSERT INTO history(songid) VALUES (?1)", [&retid])?;
-            Ok(())
-        },
+    let started_at = started_at
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let position = match event {
+        HistoryEvent::Play => None,
+        HistoryEvent::Skip { position } => Some(position),
+    };
+    db.execute(
+        "INSERT INTO history(time, songid, event, position)
+        VALUES (datetime(?1, 'unixepoch'), ?2, ?3, ?4)",
+        (started_at, track_id, event.name(), position),
     )?;
     debug!("Song history stored successfully");
     Ok(())
 }
 
-pub(crate) fn wait_for_song_change(client: &mut MPDClient) -> String {
-    let mut current_song = match client.send_command("currentsong\n".to_string()) {
-        Some(song) => song,
-        None => "".to_string(),
+/// Record play history forever, reconnecting to MPD whenever the connection drops.
+pub(crate) fn run(
+    client: &mut MPDClient,
+    db: &Connection,
+    music_dir: &Path,
+    threshold: PlayThreshold,
+    ignore: &IgnoreRules,
+    write_stickers: bool,
+    submitter: Option<&Submitter>,
+) -> ! {
+    let write_stickers = write_stickers && {
+        let available = stickers::available(client);
+        if !available {
+            warn!(
+                "MPD has no sticker database (set sticker_file in mpd.conf), not writing stickers"
+            );
+        }
+        available
     };
-    let prev_song = current_song.clone();
-    debug!("Waiting for song change. Current: {current_song}");
-
-    // TODO: don't need the entire output of currentsong if I don't want to, can just use the first
-    // line ('file' key)
-    while prev_song == current_song {
-        if let Some(val) = client.send_command("idle player\n".to_string())
-            && val == "changed: player\n"
+    let recorder = Recorder {
+        db,
+        music_dir,
+        threshold,
+        ignore,
+        write_stickers,
+        submitter,
+    };
+    let mut now_playing: Option<NowPlaying> = None;
+    let mut reconnected = false;
+    loop {
+        let waited = update_now_playing(client, &recorder, &mut now_playing, reconnected)
+            .and_then(|timeout| client.idle("player", timeout));
+        reconnected = false;
+
+        match waited {
+            Ok(val) => trace!("Woke up from idle with {val:?}"),
+            Err(CommandError::Io(err)) => {
+                warn!("Lost connection to MPD: {err}");
+                // NOTE: we can't know what was heard while MPD was away, so don't count it
+                if let Some(now_playing) = now_playing.as_mut() {
+                    now_playing.pause();
+                }
+                reconnect_with_backoff(client);
+                reconnected = true;
+            }
+            Err(CommandError::Ack(err)) => {
+                error!("MPD refused player status query: {err}");
+                thread::sleep(INITIAL_BACKOFF);
+            }
+        }
+    }
+}
+
+/// Where the daemon records to, and what it takes for a song to be recorded.
+struct Recorder<'a> {
+    db: &'a Connection,
+    music_dir: &'a Path,
+    threshold: PlayThreshold,
+    ignore: &'a IgnoreRules,
+    write_stickers: bool,
+    submitter: Option<&'a Submitter>,
+}
+
+/// Catch up on what the player is doing, recording a play once the current song crosses the
+/// threshold. Returns how long to idle before that could next happen, if it can.
+fn update_now_playing(
+    client: &mut MPDClient,
+    recorder: &Recorder,
+    now_playing: &mut Option<NowPlaying>,
+    reconnected: bool,
+) -> Result<Option<Duration>, CommandError> {
+    let status = client.send_command("status\n".to_string())?;
+    let song = client.send_command("currentsong\n".to_string())?;
+    let state = status.get("state").unwrap_or("stop");
+    let elapsed: f64 = status
+        .get("elapsed")
+        .and_then(|e| e.parse().ok())
+        .unwrap_or(0.0);
+    let duration: Option<f64> = status.get("duration").and_then(|d| d.parse().ok());
+
+    let position = now_playing.as_ref().map(|current| current.position());
+    if let Some(current) = now_playing.as_mut() {
+        current.pause();
+    }
+
+    let same_song = now_playing.as_ref().is_some_and(|current| {
+        // NOTE: compare on the queue id rather than the file so the same song queued twice in a
+        // row still counts as a change. MPD may renumber the queue when it restarts though, so
+        // after a reconnect the same file is taken to be the same song.
+        let same_entry = current.song.get("Id") == song.get("Id")
+            || (reconnected && current.song.get("file") == song.get("file"));
+        // a recorded song jumping back to the start is a repeat rather than a seek
+        let restarted = current.recorded && elapsed < 1.0 && current.last_elapsed > elapsed;
+        same_entry && !restarted
+    });
+    if !same_song {
+        debug!("Song changed to {:?}", song.get("file"));
+        // anything we heard some of but moved on from before it counted was skipped
+        if let (Some(previous), Some(position)) = (now_playing.as_ref(), position)
+            && !previous.recorded
+            && !previous.ignored
+            && !previous.listened.is_zero()
         {
-            debug!("Recieved player status update: {val}");
-            current_song = match client.send_command("currentsong\n".to_string()) {
-                Some(song) => song,
-                None => "".to_string(),
-            };
+            record_event(
+                &previous.song,
+                HistoryEvent::Skip { position },
+                previous.started_at,
+                client,
+                recorder.db,
+                recorder.music_dir,
+            )
+            .unwrap_or_else(|err| error!("Error during skip record: {err:?}"));
         }
+        let has_song = song.get("file").is_some();
+        let ignored = recorder.ignore.ignores_song(&song);
+        if ignored {
+            debug!(
+                "{:?} matches an ignore rule, not recording it",
+                song.get("file")
+            );
+        } else if state == "play"
+            && let Some(submitter) = recorder.submitter
+            && let Some(track) = TrackInfo::from_song(&song)
+        {
+            submitter.playing_now(&track);
+        }
+        *now_playing = has_song
+            .then(|| NowPlaying::new(song, recorder.threshold.for_duration(duration), ignored));
+    }
+
+    let Some(current) = now_playing.as_mut() else {
+        debug!("Queue is empty, nothing to track");
+        return Ok(None);
+    };
+    current.last_elapsed = elapsed;
+    if state == "play" {
+        current.playing_since = Some(Instant::now());
     }
+    trace!(
+        "Heard {:?} of {:?} needed for {:?}",
+        current.listened,
+        current.threshold,
+        current.song.get("file")
+    );
+
+    if !current.recorded && !current.ignored && current.remaining().is_zero() {
+        current.recorded = true;
+        // This is *technically* recoverable (though the daemon will likely be in an unideal
+        // state). In the future could kill daemon after 10 failures in a row or something.
+        record_event(
+            &current.song,
+            HistoryEvent::Play,
+            current.started_at,
+            client,
+            recorder.db,
+            recorder.music_dir,
+        )
+        .unwrap_or_else(|err| error!("Error during play record: {err:?}"));
+
+        if recorder.write_stickers
+            && let Some(file) = current.song.get("file")
+        {
+            stickers::update(recorder.db, client, file)
+                .unwrap_or_else(|err| warn!("Could not write stickers for {file}: {err}"));
+        }
 
-    current_song
+        if let Some(submitter) = recorder.submitter
+            && let Some(track) = TrackInfo::from_song(&current.song)
+        {
+            let listened_at = current
+                .started_at
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs();
+            submitter
+                .queue(recorder.db, &track, listened_at)
+                .unwrap_or_else(|err| error!("Error queueing listen for submission: {err:?}"));
+        }
+    }
+
+    Ok(match current.playing_since {
+        Some(_) if !current.recorded && !current.ignored => Some(current.remaining()),
+        _ => None,
+    })
+}
+
+fn reconnect_with_backoff(client: &mut MPDClient) {
+    let lost_at = Instant::now();
+    let mut backoff = INITIAL_BACKOFF;
+    loop {
+        thread::sleep(backoff);
+        match client.reconnect() {
+            Ok(()) => {
+                info!(
+                    "Reconnected to MPD after {:.1}s unreachable",
+                    lost_at.elapsed().as_secs_f32()
+                );
+                return;
+            }
+            Err(err) => {
+                backoff = (backoff * 2).min(MAX_BACKOFF);
+                debug!("MPD still unreachable ({err}), retrying in {backoff:?}");
+            }
+        }
+    }
 }
@@ -0,0 +1,128 @@
+use clap::ValueEnum;
+use log::debug;
+use rusqlite::Connection;
+use serde::Serialize;
+use std::io::Write;
+
+use crate::listenbrainz::Listen;
+use crate::stats::{IN_WINDOW, TimeWindow};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub(crate) enum ExportFormat {
+    /// Plays as a ListenBrainz listens array, which `eurydice import` reads back
+    ListenbrainzJson,
+    /// Every play and skip as a JSON object per line
+    Jsonl,
+    /// Every play and skip as a CSV row
+    Csv,
+}
+
+/// A `history` row with its track's metadata.
+#[derive(Debug, Serialize)]
+struct ExportedEvent {
+    time: String,
+    #[serde(skip)]
+    listened_at: i64,
+    event: String,
+    /// Seconds into the track, for skips
+    position: Option<f64>,
+    artist: Option<String>,
+    album: Option<String>,
+    title: Option<String>,
+    duration: Option<f64>,
+    file: Option<String>,
+    mbid: Option<String>,
+}
+
+// NOTE: written out by hand so an empty export still has a header, which serde only writes along
+// with the first row. Keep it in the field order of `ExportedEvent`.
+const CSV_HEADER: [&str; 9] = [
+    "time", "event", "position", "artist", "album", "title", "duration", "file", "mbid",
+];
+
+impl ExportedEvent {
+    fn listenbrainz(&self) -> serde_json::Value {
+        Listen {
+            listened_at: Some(self.listened_at),
+            artist: self.artist.as_deref(),
+            title: self.title.as_deref(),
+            album: self.album.as_deref(),
+            duration: self.duration,
+            mbid: self.mbid.as_deref(),
+        }
+        .json()
+    }
+}
+
+/// Write out the history in the window, oldest first. Returns how many rows were written.
+pub(crate) fn export(
+    db: &Connection,
+    window: &TimeWindow,
+    format: ExportFormat,
+    out: impl Write,
+) -> Result<usize, String> {
+    let query_str = format!(
+        "SELECT history.time, CAST(strftime('%s', history.time) AS INTEGER), history.event,
+            history.position, tracks.artist, tracks.album, tracks.title, tracks.lengthseconds,
+            tracks.file, tracks.mbid
+        FROM history JOIN tracks ON tracks.id = history.songid
+        WHERE {IN_WINDOW} {}
+        ORDER BY history.time, history.rowid",
+        // NOTE: ListenBrainz only has listens, a skip isn't one
+        if format == ExportFormat::ListenbrainzJson {
+            "AND history.event = 'play'"
+        } else {
+            ""
+        }
+    );
+    let events: Vec<ExportedEvent> = db
+        .prepare(&query_str)
+        .and_then(|mut query| {
+            query
+                .query_map([&window.since, &window.until], |row| {
+                    Ok(ExportedEvent {
+                        time: row.get(0)?,
+                        listened_at: row.get(1)?,
+                        event: row.get(2)?,
+                        position: row.get(3)?,
+                        artist: row.get(4)?,
+                        album: row.get(5)?,
+                        title: row.get(6)?,
+                        duration: row.get(7)?,
+                        file: row.get(8)?,
+                        mbid: row.get(9)?,
+                    })
+                })?
+                .collect()
+        })
+        .map_err(|err| format!("Could not read history: {err:?}"))?;
+    debug!("Exporting {} history rows as {format:?}", events.len());
+
+    let written = match format {
+        ExportFormat::ListenbrainzJson => serde_json::to_writer_pretty(
+            out,
+            &events.iter().map(|e| e.listenbrainz()).collect::<Vec<_>>(),
+        )
+        .map_err(|err| err.to_string()),
+        ExportFormat::Jsonl => {
+            let mut out = out;
+            events.iter().try_for_each(|e| {
+                serde_json::to_writer(&mut out, e).map_err(|err| err.to_string())?;
+                writeln!(out).map_err(|err| err.to_string())
+            })
+        }
+        ExportFormat::Csv => {
+            let mut writer = csv::WriterBuilder::new()
+                .has_headers(false)
+                .from_writer(out);
+            writer
+                .write_record(CSV_HEADER)
+                .and_then(|_| events.iter().try_for_each(|e| writer.serialize(e)))
+                .and_then(|_| writer.flush().map_err(csv::Error::from))
+                .map_err(|err| err.to_string())
+        }
+    };
+    written
+        .map(|_| events.len())
+        .map_err(|err| format!("Could not write export: {err}"))
+}
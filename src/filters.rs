@@ -0,0 +1,213 @@
+use clap::Args;
+use itertools::Itertools;
+use log::debug;
+use std::collections::HashSet;
+use std::str::FromStr;
+
+use crate::mpd_client::{MPDClient, escape, filter_value};
+
+/// Restrict surprise-me picks by their MPD tags. Repeating a filter allows any of its values, and
+/// different filters all have to match.
+#[derive(Debug, Default, Args)]
+pub(crate) struct TagFilters {
+    #[arg(long, global = true, help = "Only pick from this genre (repeatable)")]
+    genre: Vec<String>,
+
+    #[arg(
+        long,
+        global = true,
+        help = "Only pick from this year or range of years, e.g. 1972 or 1970..1979 (repeatable)"
+    )]
+    year: Vec<YearRange>,
+
+    #[arg(
+        long,
+        global = true,
+        value_parser = parse_decade,
+        help = "Only pick from this decade, e.g. 1970s (repeatable)"
+    )]
+    decade: Vec<YearRange>,
+
+    #[arg(
+        long,
+        global = true,
+        help = "Only pick tracks by this artist (repeatable)"
+    )]
+    artist: Vec<String>,
+
+    #[arg(
+        long,
+        global = true,
+        help = "Only pick tracks with this album artist (repeatable)"
+    )]
+    album_artist: Vec<String>,
+
+    #[arg(
+        long,
+        global = true,
+        help = "Only pick files of this format, e.g. flac (repeatable)"
+    )]
+    format: Vec<String>,
+
+    #[arg(
+        long,
+        global = true,
+        help = "Only pick tracks at least this long, e.g. 90s, 3m"
+    )]
+    min_length: Option<TrackLength>,
+}
+
+/// An inclusive span of years, written as `1972` or `1970..1979`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct YearRange {
+    from: u16,
+    to: u16,
+}
+
+impl FromStr for YearRange {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parse_year = |year: &str| {
+            year.trim()
+                .parse::<u16>()
+                .map_err(|err| format!("{year:?} is not a year: {err}"))
+        };
+        let (from, to) = match s.split_once("..") {
+            Some((from, to)) => (parse_year(from)?, parse_year(to)?),
+            None => (parse_year(s)?, parse_year(s)?),
+        };
+        if from > to {
+            return Err(format!("{s:?} ends before it starts"));
+        }
+        Ok(YearRange { from, to })
+    }
+}
+
+fn parse_decade(s: &str) -> Result<YearRange, String> {
+    let decade = s
+        .strip_suffix('s')
+        .and_then(|d| d.parse::<u16>().ok())
+        .filter(|d| d % 10 == 0)
+        .ok_or(format!("{s:?} is not a decade, e.g. 1970s"))?;
+    Ok(YearRange {
+        from: decade,
+        to: decade + 9,
+    })
+}
+
+/// A track length, written with a unit of `s`, `m` or `h`, e.g. `3m`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct TrackLength {
+    seconds: f32,
+}
+
+impl FromStr for TrackLength {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let Some(seconds_per_unit) = s.chars().last().and_then(|unit| match unit {
+            's' => Some(1.0),
+            'm' => Some(60.0),
+            'h' => Some(3600.0),
+            _ => None,
+        }) else {
+            return Err(format!("{s:?} needs a unit of s, m or h (e.g. 3m)"));
+        };
+        let count: f32 = s[..s.len() - 1]
+            .parse()
+            .map_err(|err| format!("{s:?} doesn't start with a number: {err}"))?;
+        Ok(TrackLength {
+            seconds: count * seconds_per_unit,
+        })
+    }
+}
+
+impl TagFilters {
+    /// One list of MPD filter expressions per filter given, any one of which is enough to match
+    /// it. MPD filters have no OR, so each alternative is a query of its own.
+    fn alternatives(&self) -> Vec<Vec<String>> {
+        let equals = |tag: &str, values: &[String]| {
+            values
+                .iter()
+                .map(|v| format!("({tag} == {})", filter_value(v)))
+                .collect_vec()
+        };
+        // NOTE: Date is usually a bare year but can be a full date, so match on how it starts
+        let years = self
+            .year
+            .iter()
+            .chain(&self.decade)
+            .map(|range| {
+                let years = (range.from..=range.to).join("|");
+                format!("(Date =~ {})", filter_value(&format!("^({years})")))
+            })
+            .collect_vec();
+        let formats = self
+            .format
+            .iter()
+            .map(|f| {
+                // NOTE: anchored to the end so `flac` doesn't also match `Some.flac.Band/...`
+                let extension: String = f
+                    .trim_start_matches('.')
+                    .chars()
+                    .flat_map(|c| match c.is_ascii_alphanumeric() {
+                        true => vec![c],
+                        false => vec!['\\', c],
+                    })
+                    .collect();
+                format!("(file =~ {})", filter_value(&format!("\\.{extension}$")))
+            })
+            .collect_vec();
+
+        [
+            equals("Genre", &self.genre),
+            years,
+            equals("Artist", &self.artist),
+            equals("AlbumArtist", &self.album_artist),
+            formats,
+        ]
+        .into_iter()
+        .filter(|alternatives| !alternatives.is_empty())
+        .collect()
+    }
+
+    /// The files matching every tag filter, or `None` if there aren't any tag filters. Uses
+    /// `search` rather than `find` so that `--genre jazz` matches `Jazz`.
+    pub(crate) fn matching_files(&self, client: &mut MPDClient) -> Option<HashSet<String>> {
+        let mut matching: Option<HashSet<String>> = None;
+        for alternatives in self.alternatives() {
+            let mut files = HashSet::new();
+            for expression in alternatives {
+                debug!("Filtering on {expression}");
+                let found = client
+                    .send_command(format!("search \"{}\"\n", escape(&expression)))
+                    .unwrap_or_else(|err| panic!("MPD could not apply filter {expression}: {err}"));
+                files.extend(found.get_all("file").map(|f| f.to_string()));
+            }
+            matching = Some(match matching {
+                Some(matching) => matching.intersection(&files).cloned().collect(),
+                None => files,
+            });
+        }
+        matching
+    }
+
+    /// Each filter given, as the MPD expressions it's searched with.
+    pub(crate) fn describe(&self) -> Vec<String> {
+        self.alternatives()
+            .iter()
+            .map(|alternatives| alternatives.join(" or "))
+            .chain(
+                self.min_length
+                    .map(|min| format!("at least {}s long", min.seconds)),
+            )
+            .collect()
+    }
+
+    /// Length isn't something MPD can filter on, so it's checked here instead.
+    pub(crate) fn allows_length(&self, seconds: f32) -> bool {
+        self.min_length.is_none_or(|min| seconds >= min.seconds)
+    }
+}
@@ -0,0 +1,68 @@
+use glob::Pattern;
+use log::trace;
+
+use crate::config::IgnoreConfig;
+use crate::mpd_response::Response;
+
+/// Tracks that should never count as music: audiobooks, podcasts, test tones and the like. They
+/// are never recorded by the daemon and never suggested or listed.
+#[derive(Debug, Default)]
+pub(crate) struct IgnoreRules {
+    paths: Vec<Pattern>,
+    genres: Vec<String>,
+    artists: Vec<String>,
+    min_duration: Option<f64>,
+}
+
+impl IgnoreRules {
+    pub(crate) fn new(config: &IgnoreConfig) -> Result<IgnoreRules, glob::PatternError> {
+        Ok(IgnoreRules {
+            paths: config
+                .paths
+                .iter()
+                .map(|p| Pattern::new(p))
+                .collect::<Result<_, _>>()?,
+            genres: config.genres.iter().map(|g| g.to_lowercase()).collect(),
+            artists: config.artists.iter().map(|a| a.to_lowercase()).collect(),
+            min_duration: config.min_duration,
+        })
+    }
+
+    /// Check a track against every rule, `file` being relative to the music directory. Genre and
+    /// artist are matched ignoring case, since tagging is rarely consistent about it.
+    fn ignores<'a>(
+        &self,
+        file: &str,
+        genres: impl IntoIterator<Item = &'a str>,
+        artists: impl IntoIterator<Item = &'a str>,
+        duration: Option<f64>,
+    ) -> bool {
+        self.paths.iter().any(|p| p.matches(file))
+            || genres
+                .into_iter()
+                .any(|g| self.genres.contains(&g.to_lowercase()))
+            || artists
+                .into_iter()
+                .any(|a| self.artists.contains(&a.to_lowercase()))
+            || self
+                .min_duration
+                .zip(duration)
+                .is_some_and(|(min, duration)| duration < min)
+    }
+
+    pub(crate) fn ignores_song(&self, song: &Response) -> bool {
+        let Some(file) = song.get("file") else {
+            return false;
+        };
+        let ignored = self.ignores(
+            file,
+            song.get_all("Genre"),
+            song.get_all("Artist"),
+            song.get("duration").and_then(|d| d.parse().ok()),
+        );
+        if ignored {
+            trace!("Ignoring {file}");
+        }
+        ignored
+    }
+}
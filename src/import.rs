@@ -0,0 +1,340 @@
+use clap::ValueEnum;
+use itertools::Itertools;
+use log::{debug, info, warn};
+use rusqlite::{Connection, OptionalExtension};
+use serde_json::Value;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::Path;
+
+use crate::ignore::IgnoreRules;
+use crate::mpd_client::MPDClient;
+use crate::tracks::{TrackInfo, find_track, upsert_track};
+
+/// Listens closer together than this to the same track are taken to be the same listen, since
+/// scrobblers and the daemon don't agree on exactly when a track started and Last.fm only keeps
+/// the minute.
+const DUPLICATE_WINDOW_SECONDS: f64 = 120.0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub(crate) enum ImportSource {
+    /// A ListenBrainz listens export, either one JSON array or JSON lines
+    Listenbrainz,
+    /// A Last.fm scrobble export as CSV
+    Lastfm,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum ListenedAt {
+    Unix(i64),
+    /// Anything sqlite's `datetime()` understands, in UTC
+    Utc(String),
+}
+
+/// One listen from an export, before it's matched to the library.
+#[derive(Debug)]
+struct Listen {
+    listened_at: ListenedAt,
+    artist: String,
+    title: String,
+    album: Option<String>,
+    /// MusicBrainz recording id
+    mbid: Option<String>,
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct ImportReport {
+    pub(crate) imported: usize,
+    pub(crate) duplicates: usize,
+    /// Rows that couldn't be read as a listen at all
+    pub(crate) unreadable: usize,
+    /// Listens that matched nothing in the library, with how many times each came up
+    pub(crate) unmatched: Vec<(String, usize)>,
+}
+
+/// Read every listen in an export. Rows that aren't listens are counted rather than failing the
+/// whole import, but a file that can't be read at all is an error.
+fn read_listens(source: ImportSource, file: &Path) -> Result<(Vec<Listen>, usize), String> {
+    let contents = fs::read_to_string(file)
+        .map_err(|err| format!("Could not read {}: {err}", file.display()))?;
+    let rows: Vec<Option<Listen>> = match source {
+        ImportSource::Listenbrainz => listenbrainz_rows(&contents)?,
+        ImportSource::Lastfm => lastfm_rows(&contents)?,
+    };
+    let total = rows.len();
+    let listens: Vec<Listen> = rows.into_iter().flatten().collect();
+    debug!(
+        "Read {} of {total} rows from {}",
+        listens.len(),
+        file.display()
+    );
+    let unreadable = total - listens.len();
+    Ok((listens, unreadable))
+}
+
+fn listenbrainz_rows(contents: &str) -> Result<Vec<Option<Listen>>, String> {
+    // NOTE: older exports are one big array, newer ones are a listen per line
+    let values: Vec<Value> = if contents.trim_start().starts_with('[') {
+        serde_json::from_str(contents).map_err(|err| format!("Invalid export: {err}"))?
+    } else {
+        contents
+            .lines()
+            .filter(|line| !line.trim().is_empty())
+            .map(|line| serde_json::from_str(line).unwrap_or(Value::Null))
+            .collect()
+    };
+    Ok(values.iter().map(listenbrainz_listen).collect())
+}
+
+fn listenbrainz_listen(value: &Value) -> Option<Listen> {
+    let metadata = &value["track_metadata"];
+    let text = |v: &Value| v.as_str().filter(|s| !s.is_empty()).map(|s| s.to_string());
+    Some(Listen {
+        listened_at: ListenedAt::Unix(value["listened_at"].as_i64()?),
+        artist: text(&metadata["artist_name"])?,
+        title: text(&metadata["track_name"])?,
+        album: text(&metadata["release_name"]),
+        mbid: text(&metadata["mbid_mapping"]["recording_mbid"])
+            .or_else(|| text(&metadata["additional_info"]["recording_mbid"])),
+    })
+}
+
+/// Last.fm exports come from third party tools in two shapes: a headerless `artist, album, track,
+/// date` with dates like `31 Jan 2021 12:34`, or one with a header naming its columns, `uts` being
+/// the unix time.
+fn lastfm_rows(contents: &str) -> Result<Vec<Option<Listen>>, String> {
+    let mut reader = csv::ReaderBuilder::new()
+        .has_headers(false)
+        .flexible(true)
+        .from_reader(contents.as_bytes());
+    let mut records = reader.records().peekable();
+
+    let header: Option<HashMap<String, usize>> = match records.peek() {
+        Some(Ok(first)) if first.iter().any(|f| f.eq_ignore_ascii_case("artist")) => Some(
+            first
+                .iter()
+                .enumerate()
+                .map(|(i, name)| (name.to_lowercase(), i))
+                .collect(),
+        ),
+        Some(Err(err)) => return Err(format!("Invalid export: {err}")),
+        _ => None,
+    };
+    if header.is_some() {
+        records.next();
+    }
+    let column = |name: &str, default: usize| {
+        header
+            .as_ref()
+            .map_or(Some(default), |h| h.get(name).copied())
+    };
+    let (artist, album, title, date) = (
+        column("artist", 0),
+        column("album", 1),
+        column("track", 2),
+        column("utc_time", 3),
+    );
+    let (uts, mbid) = (column("uts", usize::MAX), column("track_mbid", usize::MAX));
+
+    Ok(records
+        .map(|record| {
+            let record = record.ok()?;
+            let field = |i: Option<usize>| {
+                i.and_then(|i| record.get(i))
+                    .map(|f| f.trim())
+                    .filter(|f| !f.is_empty())
+                    .map(|f| f.to_string())
+            };
+            let listened_at = match field(uts).and_then(|t| t.parse().ok()) {
+                Some(uts) => ListenedAt::Unix(uts),
+                None => ListenedAt::Utc(lastfm_date(&field(date)?)?),
+            };
+            Some(Listen {
+                listened_at,
+                artist: field(artist)?,
+                title: field(title)?,
+                album: field(album),
+                mbid: field(mbid),
+            })
+        })
+        .collect())
+}
+
+/// `31 Jan 2021 12:34` (or `31 Jan 2021, 12:34`) to `2021-01-31 12:34`.
+fn lastfm_date(date: &str) -> Option<String> {
+    const MONTHS: [&str; 12] = [
+        "jan", "feb", "mar", "apr", "may", "jun", "jul", "aug", "sep", "oct", "nov", "dec",
+    ];
+    let date = date.replace(',', " ");
+    let (day, month, year, time) = date.split_whitespace().collect_tuple()?;
+    let month = MONTHS
+        .iter()
+        .position(|m| month.to_lowercase().starts_with(m))?
+        + 1;
+    let day: u32 = day.parse().ok()?;
+    let year: u32 = year.parse().ok()?;
+    Some(format!("{year:04}-{month:02}-{day:02} {time}"))
+}
+
+/// Tags as they'd be typed rather than as they're tagged: lowercase, without apostrophes, and with
+/// other punctuation and runs of whitespace as single spaces, so "AC/DC" and "ac dc" match.
+fn normalize(tag: &str) -> String {
+    tag.to_lowercase()
+        .replace(['\'', '’'], "")
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .join(" ")
+}
+
+/// The MPD library, indexed every way a listen might match it.
+struct LibraryIndex<'a> {
+    files: HashSet<&'a str>,
+    by_mbid: HashMap<&'a str, &'a TrackInfo>,
+    by_tags: HashMap<(String, String, String), Vec<&'a TrackInfo>>,
+    by_artist_title: HashMap<(String, String), Vec<&'a TrackInfo>>,
+}
+
+impl<'a> LibraryIndex<'a> {
+    fn new(library: &'a [TrackInfo]) -> LibraryIndex<'a> {
+        LibraryIndex {
+            files: library.iter().map(|t| t.file.as_str()).collect(),
+            by_mbid: library
+                .iter()
+                .filter_map(|t| Some((t.mbid.as_deref()?, t)))
+                .collect(),
+            by_tags: library.iter().into_group_map_by(|t| {
+                (
+                    normalize(&t.artist),
+                    normalize(&t.title),
+                    normalize(&t.album),
+                )
+            }),
+            by_artist_title: library
+                .iter()
+                .into_group_map_by(|t| (normalize(&t.artist), normalize(&t.title))),
+        }
+    }
+
+    /// By MusicBrainz id, then artist, title and album. Without an album, or when the album is
+    /// tagged differently, artist and title are enough as long as only one track has them.
+    fn find(&self, listen: &Listen) -> Option<&'a TrackInfo> {
+        if let Some(track) = listen.mbid.as_deref().and_then(|m| self.by_mbid.get(m)) {
+            return Some(track);
+        }
+        let (artist, title) = (normalize(&listen.artist), normalize(&listen.title));
+        // NOTE: the same track in two formats is still the same track, so take the first
+        if let Some(album) = &listen.album
+            && let Some(tracks) =
+                self.by_tags
+                    .get(&(artist.clone(), title.clone(), normalize(album)))
+        {
+            return tracks.first().copied();
+        }
+        match self.by_artist_title.get(&(artist, title)) {
+            Some(tracks) if tracks.len() == 1 => Some(tracks[0]),
+            _ => None,
+        }
+    }
+}
+
+/// Match every listen in an export to the MPD library and record it as a play at the time it was
+/// heard. Listens already recorded, by the daemon or an earlier import, are skipped.
+pub(crate) fn import(
+    db: &mut Connection,
+    client: &mut MPDClient,
+    music_dir: &Path,
+    ignore: &IgnoreRules,
+    source: ImportSource,
+    file: &Path,
+) -> Result<ImportReport, String> {
+    let (listens, unreadable) = read_listens(source, file)?;
+
+    let library: Vec<TrackInfo> = client
+        .library()
+        .map_err(|err| format!("Could not list the MPD library: {err}"))?
+        .iter()
+        .filter(|song| !ignore.ignores_song(song))
+        .filter_map(TrackInfo::from_song)
+        .collect();
+    let index = LibraryIndex::new(&library);
+    debug!(
+        "Matching {} listens against {} tracks",
+        listens.len(),
+        library.len()
+    );
+
+    let mut report = ImportReport {
+        unreadable,
+        ..Default::default()
+    };
+    record_listens(db, music_dir, &index, &listens, &mut report)
+        .map_err(|err| format!("Could not record listens: {err}"))?;
+    info!("Imported listens: {report:?}");
+    Ok(report)
+}
+
+fn record_listens(
+    db: &mut Connection,
+    music_dir: &Path,
+    index: &LibraryIndex,
+    listens: &[Listen],
+    report: &mut ImportReport,
+) -> Result<(), rusqlite::Error> {
+    let tx = db.transaction()?;
+    let mut unmatched = HashMap::<String, usize>::new();
+
+    for listen in listens {
+        let Some(track) = index.find(listen) else {
+            *unmatched
+                .entry(format!("{} - {}", listen.artist, listen.title))
+                .or_default() += 1;
+            continue;
+        };
+        let time: Option<String> = match &listen.listened_at {
+            ListenedAt::Unix(seconds) => {
+                tx.query_row("SELECT datetime(?1, 'unixepoch')", [seconds], |row| {
+                    row.get(0)
+                })?
+            }
+            ListenedAt::Utc(time) => {
+                tx.query_row("SELECT datetime(?1)", [time], |row| row.get(0))?
+            }
+        };
+        let Some(time) = time else {
+            warn!("Could not read the time of {listen:?}");
+            report.unreadable += 1;
+            continue;
+        };
+
+        if let Some(id) = find_track(&tx, track, &mut |file| index.files.contains(file))?
+            && tx
+                .query_row(
+                    "SELECT 1 FROM history WHERE songid = ?1 AND event = 'play'
+                    AND abs(julianday(time) - julianday(?2)) * 86400 < ?3",
+                    (id, &time, DUPLICATE_WINDOW_SECONDS),
+                    |_| Ok(()),
+                )
+                .optional()?
+                .is_some()
+        {
+            report.duplicates += 1;
+            continue;
+        }
+
+        let id = upsert_track(&tx, track, music_dir, 1, &mut |file| {
+            index.files.contains(file)
+        })?;
+        tx.execute(
+            "INSERT INTO history(time, songid, event) VALUES (?1, ?2, 'play')",
+            (&time, id),
+        )?;
+        report.imported += 1;
+    }
+
+    tx.commit()?;
+    report.unmatched = unmatched
+        .into_iter()
+        .sorted_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)))
+        .collect();
+    Ok(())
+}
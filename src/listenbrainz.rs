@@ -0,0 +1,258 @@
+use log::{debug, error, info, warn};
+use rusqlite::{Connection, OptionalExtension};
+use serde_json::{Value, json};
+use std::path::Path;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::config::ListenBrainzConfig;
+use crate::tracks::TrackInfo;
+
+const INITIAL_BACKOFF: Duration = Duration::from_secs(5);
+const MAX_BACKOFF: Duration = Duration::from_secs(15 * 60);
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// A listen as ListenBrainz wants it, both for submitting and in export files. Without a time
+/// it's "playing now".
+pub(crate) struct Listen<'a> {
+    pub(crate) listened_at: Option<i64>,
+    pub(crate) artist: Option<&'a str>,
+    pub(crate) title: Option<&'a str>,
+    pub(crate) album: Option<&'a str>,
+    pub(crate) duration: Option<f64>,
+    pub(crate) mbid: Option<&'a str>,
+}
+
+impl Listen<'_> {
+    pub(crate) fn json(&self) -> Value {
+        let mut additional_info = json!({
+            "media_player": "MPD",
+            "submission_client": "eurydice",
+        });
+        if let Some(mbid) = self.mbid {
+            additional_info["recording_mbid"] = json!(mbid);
+        }
+        if let Some(duration) = self.duration {
+            additional_info["duration_ms"] = json!((duration * 1000.0).round() as i64);
+        }
+        let mut listen = json!({
+            "track_metadata": {
+                "artist_name": self.artist,
+                "track_name": self.title,
+                "release_name": self.album,
+                "additional_info": additional_info,
+            },
+        });
+        if let Some(listened_at) = self.listened_at {
+            listen["listened_at"] = json!(listened_at);
+        }
+        listen
+    }
+}
+
+fn listen(track: &TrackInfo, listened_at: Option<u64>) -> Value {
+    Listen {
+        listened_at: listened_at.map(|t| t as i64),
+        artist: Some(&track.artist),
+        title: Some(&track.title),
+        album: Some(&track.album),
+        duration: Some(track.duration),
+        mbid: track.mbid.as_deref(),
+    }
+    .json()
+}
+
+enum Message {
+    /// Something new is in the outbox
+    Queued,
+    PlayingNow(Value),
+}
+
+/// Hands listens to a background thread to submit, so a slow or unreachable server never holds
+/// up recording plays.
+pub(crate) struct Submitter {
+    sender: Sender<Message>,
+}
+
+impl Submitter {
+    /// Start submitting, if there's a token to submit with.
+    pub(crate) fn start(config: &ListenBrainzConfig, db_path: &Path) -> Option<Submitter> {
+        let Some(token) = config.token.clone() else {
+            debug!("No ListenBrainz token configured, not submitting listens");
+            return None;
+        };
+        let db = Connection::open(db_path).expect("Could not open db connection for submitting");
+        let api = Api::new(&config.url, token);
+        let (sender, receiver) = mpsc::channel();
+        thread::Builder::new()
+            .name("listenbrainz".to_string())
+            .spawn(move || submit_forever(&api, &db, receiver))
+            .expect("Could not start the ListenBrainz submitter");
+        info!("Submitting listens to {}", config.url);
+        Some(Submitter { sender })
+    }
+
+    /// Put a play in the outbox, where it stays until it has been submitted.
+    pub(crate) fn queue(
+        &self,
+        db: &Connection,
+        track: &TrackInfo,
+        listened_at: u64,
+    ) -> Result<(), rusqlite::Error> {
+        db.execute(
+            "INSERT INTO outbox(listen) VALUES (?1)",
+            [listen(track, Some(listened_at)).to_string()],
+        )?;
+        // NOTE: the thread lives as long as the daemon, and the listen is safe in the outbox even
+        // if it somehow doesn't
+        let _ = self.sender.send(Message::Queued);
+        Ok(())
+    }
+
+    pub(crate) fn playing_now(&self, track: &TrackInfo) {
+        let _ = self.sender.send(Message::PlayingNow(listen(track, None)));
+    }
+}
+
+/// Why a submission didn't go through.
+#[derive(Debug)]
+enum Failure {
+    /// The server will never take it as it is
+    Rejected(String),
+    /// It might go through later, after this long if the server said
+    Retry(Option<Duration>, String),
+}
+
+struct Api {
+    agent: ureq::Agent,
+    submit_url: String,
+    token: String,
+}
+
+impl Api {
+    fn new(url: &str, token: String) -> Api {
+        Api {
+            agent: ureq::Agent::config_builder()
+                .http_status_as_error(false)
+                .timeout_global(Some(REQUEST_TIMEOUT))
+                .build()
+                .into(),
+            submit_url: url.trim_end_matches('/').to_string() + "/1/submit-listens",
+            token,
+        }
+    }
+
+    fn submit(&self, listen_type: &str, listen: Value) -> Result<(), Failure> {
+        let body = json!({ "listen_type": listen_type, "payload": [listen] });
+        let mut response = self
+            .agent
+            .post(&self.submit_url)
+            .header("Authorization", format!("Token {}", self.token))
+            .header("Content-Type", "application/json")
+            .send(body.to_string())
+            .map_err(|err| Failure::Retry(None, err.to_string()))?;
+        if response.status().is_success() {
+            return Ok(());
+        }
+
+        let status = response.status().as_u16();
+        let reset_in = response
+            .headers()
+            .get("X-RateLimit-Reset-In")
+            .and_then(|r| r.to_str().ok())
+            .and_then(|r| r.parse().ok())
+            .map(Duration::from_secs);
+        let reason = format!(
+            "{status}: {}",
+            response.body_mut().read_to_string().unwrap_or_default()
+        );
+        Err(match status {
+            429 => Failure::Retry(reset_in, reason),
+            // a bad token gets fixed in the config, the listens shouldn't be lost in the meantime
+            401 | 403 => Failure::Retry(None, reason),
+            400..=499 => Failure::Rejected(reason),
+            _ => Failure::Retry(None, reason),
+        })
+    }
+}
+
+/// Submit everything in the outbox, oldest first, until it's empty or the server can't take any
+/// more for now. Returns how long to wait before trying again in that case, if the server said.
+fn flush(api: &Api, db: &Connection) -> Result<(), Option<Duration>> {
+    let next = || {
+        db.query_row(
+            "SELECT id, listen FROM outbox ORDER BY id LIMIT 1",
+            [],
+            |row| Ok((row.get::<_, i64>(0)?, row.get::<_, String>(1)?)),
+        )
+        .optional()
+    };
+    let mut submitted = 0;
+    loop {
+        let (id, listen) = match next() {
+            Ok(Some(next)) => next,
+            Ok(None) => break,
+            Err(err) => {
+                error!("Could not read the outbox: {err:?}");
+                return Err(None);
+            }
+        };
+        let listen: Value = serde_json::from_str(&listen).unwrap_or(Value::Null);
+        match api.submit("single", listen) {
+            Ok(()) => submitted += 1,
+            Err(Failure::Rejected(reason)) => {
+                error!("ListenBrainz rejected listen {id}, dropping it: {reason}");
+            }
+            Err(Failure::Retry(after, reason)) => {
+                warn!("Could not submit listens ({reason}), will retry");
+                return Err(after);
+            }
+        }
+        if let Err(err) = db.execute("DELETE FROM outbox WHERE id = ?1", [id]) {
+            error!("Could not clear listen {id} from the outbox: {err:?}");
+            return Err(None);
+        }
+    }
+    if submitted > 0 {
+        info!("Submitted {submitted} listens");
+    }
+    Ok(())
+}
+
+fn submit_forever(api: &Api, db: &Connection, receiver: Receiver<Message>) {
+    let mut backoff = INITIAL_BACKOFF;
+    let mut retry_at: Option<Instant> = None;
+    loop {
+        if retry_at.is_none_or(|at| Instant::now() >= at) {
+            match flush(api, db) {
+                Ok(()) => {
+                    retry_at = None;
+                    backoff = INITIAL_BACKOFF;
+                }
+                Err(after) => {
+                    let wait = after.unwrap_or(backoff);
+                    debug!("Retrying submissions in {wait:?}");
+                    retry_at = Some(Instant::now() + wait);
+                    backoff = (backoff * 2).min(MAX_BACKOFF);
+                }
+            }
+        }
+
+        let message = match retry_at {
+            Some(at) => receiver.recv_timeout(at.saturating_duration_since(Instant::now())),
+            None => receiver.recv().map_err(|_| RecvTimeoutError::Disconnected),
+        };
+        match message {
+            // NOTE: "playing now" is stale by the time it could be retried, so it's only tried
+            // when the server is known to be up
+            Ok(Message::PlayingNow(listen)) if retry_at.is_none() => {
+                if let Err(err) = api.submit("playing_now", listen) {
+                    debug!("Could not submit playing now: {err:?}");
+                }
+            }
+            Ok(_) | Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => return,
+        }
+    }
+}
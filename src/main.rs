@@ -1,16 +1,36 @@
 use clap::{Parser, Subcommand};
-use log::{error, info};
+use log::{error, info, warn};
 use rusqlite::Connection;
-use std::{env, fs};
+use std::{env, fs, path::PathBuf};
 
 use crate::collection::CollectionFormat;
+use crate::export::ExportFormat;
+use crate::filters::TagFilters;
+use crate::import::ImportSource;
+use crate::mpd_client::QueueMode;
+use crate::sampling::Weighting;
+use crate::stats::{StatsFormat, StatsPeriod};
+use crate::surprise_me::Age;
 
 mod collection;
+mod config;
 mod daemon;
+mod export;
+mod filters;
+mod ignore;
+mod import;
+mod listenbrainz;
+mod migrations;
 mod mpd_client;
+mod mpd_response;
 mod never_played;
+mod ratings;
+mod sampling;
 mod stats;
+mod stickers;
 mod surprise_me;
+mod tracks;
+mod wrapped;
 
 #[derive(Debug, Parser)]
 #[command(
@@ -23,6 +43,13 @@ as entire albums.
 "
 )]
 struct Cli {
+    #[arg(
+        long,
+        global = true,
+        help = "Config file to use instead of $XDG_CONFIG_HOME/eurydice/config.toml"
+    )]
+    config: Option<PathBuf>,
+
     #[command(subcommand)]
     command: Commands,
 }
@@ -34,20 +61,241 @@ enum Commands {
     SurpriseMe {
         #[command(subcommand)]
         opt: SurpriseMeCommand,
+
+        #[arg(
+            short,
+            long,
+            global = true,
+            help = "How picks are weighted against plays [default: recency-decay]"
+        )]
+        weighting: Option<Weighting>,
+
+        #[arg(
+            long,
+            global = true,
+            help = "Seed the random picks, so the same seed and history give the same picks"
+        )]
+        seed: Option<u64>,
+
+        #[arg(
+            long,
+            global = true,
+            value_parser = clap::value_parser!(u8).range(1..=5),
+            help = "Only pick tracks (or albums, on average) rated at least this many stars"
+        )]
+        min_rating: Option<u8>,
+
+        #[arg(
+            long,
+            global = true,
+            value_name = "NAME",
+            conflicts_with_all = ["replace_queue", "insert_next"],
+            help = "Save the picks as a stored playlist instead of queueing them, replacing any playlist of that name"
+        )]
+        save_as: Option<String>,
+
+        #[arg(
+            long,
+            global = true,
+            default_value_t = false,
+            conflicts_with = "insert_next",
+            help = "Clear the queue and play the picks instead of adding them to the end"
+        )]
+        replace_queue: bool,
+
+        #[arg(
+            long,
+            global = true,
+            default_value_t = false,
+            help = "Queue the picks straight after the current song instead of at the end"
+        )]
+        insert_next: bool,
+
+        #[arg(
+            long,
+            global = true,
+            default_value_t = false,
+            help = "Show the picks with their play counts and weights instead of queueing or saving them"
+        )]
+        dry_run: bool,
+
+        #[arg(
+            long,
+            global = true,
+            default_value_t = false,
+            help = "Show how the picks were narrowed down from the library"
+        )]
+        explain: bool,
+
+        #[command(flatten)]
+        filters: TagFilters,
+    },
+    #[command(about = "Write out the play history, for archiving or moving to other tools")]
+    Export {
+        #[arg(long, help = "What to write the history as")]
+        format: ExportFormat,
+
+        #[arg(
+            long,
+            help = "Only export listens on or after this date (e.g. 2026-01-01)"
+        )]
+        since: Option<String>,
+    },
+    #[command(about = "Import listens from a Last.fm or ListenBrainz export")]
+    Import {
+        #[arg(long, help = "Where the export came from")]
+        from: ImportSource,
+
+        #[arg(help = "The exported listens")]
+        file: PathBuf,
     },
     #[command(about = "Output some interesting stats about played tracks")]
-    Stats,
+    Stats {
+        #[command(subcommand)]
+        opt: Option<StatsCommand>,
+
+        #[arg(
+            long,
+            help = "Only count plays on or after this date (e.g. 2026-01-01)",
+            conflicts_with = "period"
+        )]
+        since: Option<String>,
+
+        #[arg(
+            long,
+            help = "Only count plays on or before this date (e.g. 2026-06-30)"
+        )]
+        until: Option<String>,
+
+        #[arg(
+            short,
+            long,
+            help = "Only count plays from the last week, month or year"
+        )]
+        period: Option<StatsPeriod>,
+
+        #[arg(short, long, help = "Output Format")]
+        format: Option<StatsFormat>,
+    },
     #[command(
         about = "List out tracks that are in the collection, but have no plays in the history."
     )]
     NeverPlayed,
     #[command(about = "Start the eurydice daemon to record MPD play history.")]
-    Daemon,
+    Daemon {
+        #[arg(
+            long,
+            value_parser = daemon::parse_play_fraction,
+            help = "Fraction of a track that must be heard for it to count as a play [default: 0.5]"
+        )]
+        play_fraction: Option<f64>,
+
+        #[arg(
+            long,
+            value_parser = daemon::parse_play_seconds,
+            help = "Seconds of a track after which it always counts as a play, however long it is [default: 240]"
+        )]
+        play_seconds: Option<f64>,
+    },
+    #[command(arg_required_else_help = true)]
+    #[command(about = "Manage the eurydice database")]
+    Db {
+        #[command(subcommand)]
+        opt: DbCommand,
+    },
+    #[command(about = "Rate the current song, or the given one, out of 5 stars (0 clears it)")]
+    Rate {
+        #[arg(value_parser = clap::value_parser!(u8).range(0..=5))]
+        stars: u8,
+
+        #[arg(help = "A song in the MPD library, instead of the current one")]
+        file: Option<String>,
+    },
+    #[command(about = "Mark the current song, or the given one, as a favorite")]
+    Love {
+        #[arg(help = "A song in the MPD library, instead of the current one")]
+        file: Option<String>,
+    },
+    #[command(about = "Unmark the current song, or the given one, as a favorite")]
+    Unlove {
+        #[arg(help = "A song in the MPD library, instead of the current one")]
+        file: Option<String>,
+    },
+    #[command(about = "Never pick the current song, or the given one, in surprise-me")]
+    Ban {
+        #[arg(help = "A song in the MPD library, instead of the current one")]
+        file: Option<String>,
+    },
+    #[command(about = "Let surprise-me pick the current song, or the given one, again")]
+    Unban {
+        #[arg(help = "A song in the MPD library, instead of the current one")]
+        file: Option<String>,
+    },
+    #[command(arg_required_else_help = true)]
+    #[command(about = "Share play counts with other MPD clients through song stickers")]
+    Stickers {
+        #[command(subcommand)]
+        opt: StickersCommand,
+    },
     #[command(about = "collection information")]
     Collection {
         #[arg(short, long, help = "Output Format")]
         format: Option<CollectionFormat>,
     },
+    #[command(arg_required_else_help = true)]
+    #[command(about = "Inspect the eurydice config file")]
+    Config {
+        #[command(subcommand)]
+        opt: ConfigCommand,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+enum ConfigCommand {
+    #[command(about = "Print the effective config, defaults included")]
+    Show,
+}
+
+#[derive(Debug, Subcommand)]
+enum StatsCommand {
+    #[command(about = "A year in review of your listening")]
+    Wrapped {
+        #[arg(
+            short,
+            long,
+            help = "Year to review. If not given, the current year is used."
+        )]
+        year: Option<i32>,
+
+        #[arg(
+            long,
+            help = "Also write the review as a self-contained HTML page to this path"
+        )]
+        html: Option<PathBuf>,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+enum StickersCommand {
+    #[command(about = "Write the playCount and lastPlayed stickers of every recorded track")]
+    Sync,
+}
+
+#[derive(Debug, Subcommand)]
+enum DbCommand {
+    #[command(about = "Upgrade the database schema, backing it up first")]
+    Migrate {
+        #[arg(
+            long,
+            default_value_t = false,
+            help = "List the migrations that would be applied without changing anything"
+        )]
+        dry_run: bool,
+    },
+    #[command(
+        about = "Match recorded tracks against the MPD library by file and MusicBrainz id, merging duplicates"
+    )]
+    Reconcile,
 }
 
 #[derive(Debug, Subcommand)]
@@ -57,15 +305,38 @@ enum SurpriseMeCommand {
         #[arg(
             short,
             long,
-            help = "Number of albums to queue up. If not given, one album will be added to the queue."
+            help = "Number of albums to queue up. If not given, one album (or album_count from the config) will be added to the queue."
         )]
         count: Option<u16>,
+
+        #[arg(
+            long,
+            help = "Leave out anything played within this long, e.g. 30d, 2w, 6m (0d for no limit)"
+        )]
+        not_played_within: Option<Age>,
     },
     #[command(about = "Add a \"mixtape\" of less-played songs to your queue")]
     Playlist {
-        #[arg(short, long, help = "Length of playlist to build, in minutes")]
+        #[arg(
+            short,
+            long,
+            help = "Length of playlist to build, in minutes [default: 60]"
+        )]
         target_length: Option<f32>,
 
+        #[arg(
+            long,
+            help = "How many minutes over or under the target length is close enough [default: 2]"
+        )]
+        tolerance: Option<f32>,
+
+        #[arg(
+            long,
+            default_value_t = false,
+            help = "Fail if no combination of tracks fits the target length within the tolerance, instead of queuing the closest fit"
+        )]
+        strict: bool,
+
         #[arg(
             short,
             long,
@@ -73,124 +344,358 @@ enum SurpriseMeCommand {
             help = "Enforce that all tracks must come from the same artist (which will be selected randomly)"
         )]
         same_artist: bool,
+
+        #[arg(
+            long,
+            help = "Leave out anything played within this long, e.g. 30d, 2w, 6m (0d for no limit)"
+        )]
+        not_played_within: Option<Age>,
     },
 }
 
 fn main() -> std::io::Result<()> {
     env_logger::init();
-    info!("MPD client initilized succesfully");
-    let mut client = mpd_client::MPDClient::connect();
-    let config_response = client
-        .send_command("config\n".to_string())
-        .expect("MPD config message returned unexpected response");
-    let mpd_music_dir = config_response
-        .lines()
-        .next()
-        .expect("MPD config message returned no data")
-        .split(": ")
-        .last()
-        .expect("MPD config message did not contain music directory");
-    let music_dir = std::path::Path::new(&mpd_music_dir);
-
-    let data_path = env::var("XDG_DATA_HOME")
-        .unwrap_or(env::var("HOME").expect("Home env var not set") + "/.local/share/")
-        + "eurydice/";
-    fs::create_dir_all(&data_path)?;
-    let db = Connection::open(data_path + "db.db3").expect("Could not open db connection");
-    match setup_db(&db) {
+    let args = Cli::parse();
+
+    let config = config::load(args.config.as_ref())
+        .unwrap_or_else(|err| panic!("Invalid config file {err}"));
+    if let Commands::Config {
+        opt: ConfigCommand::Show,
+    } = &args.command
+    {
+        print!("{}", config.show());
+        return Ok(());
+    }
+    let ignore = ignore::IgnoreRules::new(&config.ignore)
+        .unwrap_or_else(|err| panic!("Invalid ignore path in config: {err}"));
+
+    let db_path = match &config.storage.database {
+        Some(db_path) => {
+            if let Some(parent) = db_path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            db_path.clone()
+        }
+        None => {
+            let data_path = env::var("XDG_DATA_HOME")
+                .unwrap_or(env::var("HOME").expect("Home env var not set") + "/.local/share/")
+                + "eurydice/";
+            fs::create_dir_all(&data_path)?;
+            PathBuf::from(data_path + "db.db3")
+        }
+    };
+    let mut db = Connection::open(&db_path).expect("Could not open db connection");
+
+    // NOTE: migrating by hand has to happen before the automatic migration below, and doesn't
+    // need MPD at all
+    if let Commands::Db { opt } = &args.command
+        && let DbCommand::Migrate { dry_run } = opt
+    {
+        let version = migrations::current_version(&db)
+            .unwrap_or_else(|err| panic!("Could not read db version: {err:?}"));
+        let applied = migrations::migrate(&mut db, &db_path, *dry_run)
+            .unwrap_or_else(|err| panic!("Failed db migration: {err:?}"));
+        if applied.is_empty() {
+            println!("Database is up to date at version {version}");
+        }
+        for migration in applied {
+            println!(
+                "{} migration {}: {}",
+                if *dry_run { "Would apply" } else { "Applied" },
+                migration.version,
+                migration.description
+            );
+        }
+        return Ok(());
+    }
+
+    match migrations::migrate(&mut db, &db_path, false) {
         Ok(_) => {}
         Err(e) => panic!("Failed db initialization: {e:?}"),
     }
 
     info!("DB connection initilized succesfully");
 
-    let args = Cli::parse();
+    // NOTE: exporting only needs the database, so it works with MPD down too
+    if let Commands::Export { format, since } = &args.command {
+        let exported = stats::TimeWindow::new(&db, since.clone(), None, None)
+            .and_then(|window| export::export(&db, &window, *format, std::io::stdout().lock()));
+        match exported {
+            Ok(count) => info!("Exported {count} history rows"),
+            Err(e) => {
+                eprintln!("{e}");
+                std::process::exit(1);
+            }
+        }
+        return Ok(());
+    }
+
+    let mut client = mpd_client::MPDClient::connect(&config.connection);
+    info!("MPD client initilized succesfully");
+    // NOTE: `config` is only available to local clients, so a remote MPD leaves us without a
+    // music directory unless it's configured. Tracks are then recorded and queued by their
+    // library-relative URI.
+    let music_dir = if let Some(music_dir) = &config.connection.music_directory {
+        music_dir.clone()
+    } else if client.is_local() {
+        let config_response = client
+            .send_command("config\n".to_string())
+            .expect("MPD config message returned unexpected response");
+        PathBuf::from(
+            config_response
+                .get("music_directory")
+                .expect("MPD config message did not contain music directory"),
+        )
+    } else {
+        warn!("MPD connection is not local, music directory is unknown");
+        PathBuf::new()
+    };
+    let music_dir = music_dir.as_path();
 
     match args.command {
-        Commands::Stats => {
+        Commands::Stats {
+            opt: Some(StatsCommand::Wrapped { year, html }),
+            ..
+        } => {
+            let year = year.unwrap_or_else(|| {
+                wrapped::current_year(&db)
+                    .unwrap_or_else(|err| panic!("Could not read current year: {err:?}"))
+            });
+            let report = wrapped::build_wrapped(&db, &mut client, year, config.stats.top_limit);
+            println!("{}", wrapped::wrapped_table(&report));
+            if let Some(html) = html {
+                match fs::write(&html, wrapped::wrapped_html(&report)) {
+                    Ok(()) => println!("Wrote {}", html.display()),
+                    Err(e) => {
+                        println!("Could not write {}", html.display());
+                        error!("Error writing wrapped html: {e}")
+                    }
+                }
+            }
+        }
+        Commands::Stats {
+            opt: None,
+            since,
+            until,
+            period,
+            format,
+        } => {
             // TODO: pass params through for limit and unique etc
-            stats::print_stats_table(&db);
+            match stats::TimeWindow::new(&db, since, until, period) {
+                Ok(window) => println!(
+                    "{}",
+                    stats::stats_information(
+                        &db,
+                        &window,
+                        format.unwrap_or(config.stats.format),
+                        config.stats.top_limit
+                    )
+                ),
+                Err(e) => println!("Invalid stats period: {e}"),
+            }
         }
-        Commands::NeverPlayed => match never_played::never_played(&db, &mut client, music_dir) {
-            Ok(tracks) => println!("{tracks}"),
-            Err(e) => {
-                println!("Could not find unplayed tracks");
-                error!("Error finding unplayed tracks: {e}")
+        Commands::NeverPlayed => {
+            match never_played::never_played(&db, &mut client, music_dir, &ignore) {
+                Ok(tracks) => println!("{tracks}"),
+                Err(e) => {
+                    println!("Could not find unplayed tracks");
+                    error!("Error finding unplayed tracks: {e}")
+                }
+            }
+        }
+        Commands::Collection { format } => println!(
+            "{}",
+            collection::collection_information(
+                &mut client,
+                music_dir,
+                format.unwrap_or(CollectionFormat::Summary),
+                &config.collection.custom_items,
+                &ignore
+            )
+        ),
+        Commands::Import { from, file } => {
+            match import::import(&mut db, &mut client, music_dir, &ignore, from, &file) {
+                Ok(report) => {
+                    println!(
+                        "Imported {} listens, skipped {} already recorded and {} unreadable",
+                        report.imported, report.duplicates, report.unreadable
+                    );
+                    if !report.unmatched.is_empty() {
+                        println!(
+                            "{} listens were not found in the library:",
+                            report
+                                .unmatched
+                                .iter()
+                                .map(|(_, count)| count)
+                                .sum::<usize>()
+                        );
+                        for (track, count) in report.unmatched {
+                            println!("{count:>6}  {track}");
+                        }
+                    }
+                }
+                Err(e) => {
+                    println!("{e}");
+                    std::process::exit(1);
+                }
             }
+        }
+        Commands::Daemon {
+            play_fraction,
+            play_seconds,
+        } => daemon::run(
+            &mut client,
+            &db,
+            music_dir,
+            daemon::PlayThreshold {
+                fraction: play_fraction.unwrap_or(config.daemon.play_fraction),
+                max_seconds: play_seconds.unwrap_or(config.daemon.play_seconds),
+            },
+            &ignore,
+            config.daemon.write_stickers,
+            listenbrainz::Submitter::start(&config.listenbrainz, &db_path).as_ref(),
+        ),
+        Commands::Config { .. } => unreachable!("Config is handled before anything else"),
+        Commands::Export { .. } => unreachable!("Export is handled before connecting to MPD"),
+        Commands::Db { opt } => match opt {
+            DbCommand::Migrate { .. } => {
+                unreachable!("Migrations are handled before MPD connects")
+            }
+            DbCommand::Reconcile => match tracks::reconcile(&mut db, &mut client, music_dir) {
+                Ok(report) => println!(
+                    "Identified {} tracks, merged {} duplicates, {} tracks not found in the MPD library",
+                    report.identified, report.merged, report.unidentified
+                ),
+                Err(e) => {
+                    println!("Could not reconcile tracks");
+                    error!("Error reconciling tracks: {e}")
+                }
+            },
         },
-        Commands::Collection { format } => match format {
-            Some(format) => println!(
-                "{}",
-                collection::collection_information(&mut client, music_dir, format)
-            ),
-            None => println!(
-                "{}",
-                collection::collection_information(
-                    &mut client,
-                    music_dir,
-                    CollectionFormat::Summary
-                )
+        Commands::Rate { .. }
+        | Commands::Love { .. }
+        | Commands::Unlove { .. }
+        | Commands::Ban { .. }
+        | Commands::Unban { .. } => {
+            let (file, change) = match args.command {
+                Commands::Rate { stars, file } => {
+                    (file, ratings::Change::Stars((stars > 0).then_some(stars)))
+                }
+                Commands::Love { file } => (file, ratings::Change::Loved(true)),
+                Commands::Unlove { file } => (file, ratings::Change::Loved(false)),
+                Commands::Ban { file } => (file, ratings::Change::Banned(true)),
+                Commands::Unban { file } => (file, ratings::Change::Banned(false)),
+                _ => unreachable!("Only rating commands get here"),
+            };
+            match ratings::apply(&db, &mut client, music_dir, file, change) {
+                Ok(file) => println!("{} {file}", change.describe()),
+                Err(e) => {
+                    println!("{e}");
+                    std::process::exit(1);
+                }
+            }
+        }
+        Commands::Stickers {
+            opt: StickersCommand::Sync,
+        } => match stickers::sync(&db, &mut client) {
+            Ok(report) => println!(
+                "Wrote stickers for {} tracks, {} tracks not found in the MPD library",
+                report.written, report.missing
             ),
+            Err(e) => {
+                println!("{e}");
+                std::process::exit(1);
+            }
         },
-        Commands::Daemon => loop {
-            let new_song = daemon::wait_for_song_change(&mut client);
-            // This is *technically* recoverable (though the daemon will likely be in an unideal
-            // state). In the future could kill daemon after 10 song change failures in a row or
-            // something.
-            daemon::handle_song_change(new_song, &db, music_dir)
-                .unwrap_or_else(|err| error!("Error during song change handle: {err:?}"))
-        },
-        Commands::SurpriseMe { opt } => match opt {
-            SurpriseMeCommand::Album { count } => {
-                let tracks = surprise_me::create_album_playlist(&db, count)
-                    .unwrap_or_else(|err| panic!("Error creating mixtape: {err:?}"));
-                client.add_to_queue(&tracks);
-                info!(
-                    "Album request - Successfully added {} tracks to playlist",
-                    tracks.len()
-                );
+        Commands::SurpriseMe {
+            opt,
+            weighting,
+            seed,
+            min_rating,
+            save_as,
+            replace_queue,
+            insert_next,
+            dry_run,
+            explain,
+            filters,
+        } => {
+            let mut sampler =
+                sampling::Sampler::new(weighting.unwrap_or(config.surprise_me.weighting), seed);
+            let library =
+                surprise_me::library_tracks(&db, &mut client, music_dir, &ignore, &filters)
+                    .unwrap_or_else(|err| panic!("Error reading play history: {err:?}"));
+            let selection = match opt {
+                SurpriseMeCommand::Album {
+                    count,
+                    not_played_within,
+                } => surprise_me::create_album_playlist(
+                    library,
+                    count.unwrap_or(config.surprise_me.album_count),
+                    not_played_within.or(config.surprise_me.not_played_within),
+                    min_rating,
+                    &mut sampler,
+                ),
+                SurpriseMeCommand::Playlist {
+                    target_length,
+                    tolerance,
+                    strict,
+                    same_artist,
+                    not_played_within,
+                } => {
+                    let length = surprise_me::PlaylistLength {
+                        target: target_length.unwrap_or(config.surprise_me.target_length),
+                        tolerance: tolerance.unwrap_or(config.surprise_me.length_tolerance),
+                        strict,
+                    };
+                    match surprise_me::create_track_playlist(
+                        library,
+                        length,
+                        same_artist,
+                        config.surprise_me.candidate_limit,
+                        not_played_within.or(config.surprise_me.not_played_within),
+                        min_rating,
+                        &mut sampler,
+                    ) {
+                        Ok(tracks) => tracks,
+                        Err(e) => {
+                            println!("Could not build a playlist: {e}");
+                            std::process::exit(1);
+                        }
+                    }
+                }
+            };
+            if explain {
+                println!("{}", selection.explanation(&filters));
             }
-            SurpriseMeCommand::Playlist {
-                target_length,
-                same_artist,
-            } => {
-                let tracks = surprise_me::create_track_playlist(&db, target_length, same_artist)
-                    .unwrap_or_else(|err| panic!("Error creating mixtape: {err:?}"));
-                client.add_to_queue(&tracks);
-                info!(
-                    "Playlist request - Successfully added {} tracks to playlist",
-                    tracks.len()
-                );
+            if dry_run {
+                println!("{}", selection.picks_table());
+                return Ok(());
             }
-        },
+            let tracks = selection.tracks;
+            // NOTE: an empty pick would still clear the queue or playlist it was replacing
+            if tracks.is_empty() {
+                println!("Nothing matched to pick from, so nothing was queued or saved");
+                return Ok(());
+            }
+            match save_as {
+                Some(name) => {
+                    client.save_playlist(&name, &tracks, music_dir);
+                    info!("Saved {} tracks to playlist {name}", tracks.len());
+                }
+                None => {
+                    let mode = if replace_queue {
+                        QueueMode::Replace
+                    } else if insert_next {
+                        QueueMode::InsertNext
+                    } else {
+                        QueueMode::Append
+                    };
+                    client.add_to_queue(&tracks, music_dir, mode);
+                    info!("Successfully added {} tracks to the queue", tracks.len());
+                }
+            }
+        }
     }
 
     Ok(())
 }
-
-fn setup_db(db: &Connection) -> std::result::Result<(), rusqlite::Error> {
-    db.execute(
-        "CREATE TABLE IF NOT EXISTS tracks (
-            id INTEGER PRIMARY KEY,
-            title TEXT,
-            artist TEXT,
-            album TEXT,
-            lengthseconds REAL,
-            playcount INTEGER,
-            path TEXT,
-            UNIQUE (title, artist, album)
-        )",
-        (),
-    )?;
-
-    db.execute(
-        "CREATE TABLE IF NOT EXISTS history (
-            time DATETIME DEFAULT CURRENT_TIMESTAMP,
-            songid INTEGER,
-            FOREIGN KEY (songid) REFERENCES tracks(id)
-        )",
-        (),
-    )?;
-
-    Ok(())
-}
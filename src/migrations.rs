@@ -0,0 +1,241 @@
+use itertools::Itertools;
+use log::{debug, info};
+use rusqlite::{Connection, Transaction, ffi};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// A single schema change. The database's `PRAGMA user_version` records the version of the last
+/// one applied, so each migration runs exactly once and in order.
+pub(crate) struct Migration {
+    pub(crate) version: u32,
+    pub(crate) description: &'static str,
+    apply: fn(&Transaction) -> Result<(), rusqlite::Error>,
+}
+
+// NOTE: never edit or reorder a migration once it has shipped, only append new ones
+const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        description: "Create tracks and history tables",
+        apply: create_tables,
+    },
+    Migration {
+        version: 2,
+        description: "Add skip tracking columns to history",
+        apply: add_history_events,
+    },
+    Migration {
+        version: 3,
+        description: "Identify tracks by file and MusicBrainz id instead of tags",
+        apply: track_identity,
+    },
+    Migration {
+        version: 4,
+        description: "Add an outbox of listens waiting to be submitted to ListenBrainz",
+        apply: create_outbox,
+    },
+    Migration {
+        version: 5,
+        description: "Add ratings, loved and banned tracks",
+        apply: create_ratings,
+    },
+];
+
+pub(crate) fn current_version(db: &Connection) -> Result<u32, rusqlite::Error> {
+    db.query_row("PRAGMA user_version", [], |row| row.get(0))
+}
+
+pub(crate) fn pending(db: &Connection) -> Result<Vec<&'static Migration>, rusqlite::Error> {
+    let version = current_version(db)?;
+    Ok(MIGRATIONS.iter().filter(|m| m.version > version).collect())
+}
+
+/// Apply every pending migration in a single transaction, backing up the database file first if
+/// it already has data in it. Returns the migrations that were (or, for a dry run, would be)
+/// applied.
+pub(crate) fn migrate(
+    db: &mut Connection,
+    db_path: &Path,
+    dry_run: bool,
+) -> Result<Vec<&'static Migration>, rusqlite::Error> {
+    let pending = pending(db)?;
+    if pending.is_empty() || dry_run {
+        debug!("{} pending migrations, dry run: {dry_run}", pending.len());
+        return Ok(pending);
+    }
+
+    let has_tables: bool = db.query_row(
+        "SELECT count(*) > 0 FROM sqlite_master WHERE type = 'table'",
+        [],
+        |row| row.get(0),
+    )?;
+    if has_tables {
+        let backup = backup_path(db_path, current_version(db)?);
+        info!(
+            "Backing up database to {} before migrating",
+            backup.display()
+        );
+        db.execute("VACUUM INTO ?1", [backup.to_str().unwrap()])?;
+    }
+
+    // NOTE: rebuilding a table that others reference trips foreign key checks part way through, and
+    // the pragma can't be changed inside a transaction. See
+    // https://www.sqlite.org/lang_altertable.html#otheralter
+    // Whatever the connection had is put back afterwards, even if a migration fails.
+    let foreign_keys: bool = db.pragma_query_value(None, "foreign_keys", |row| row.get(0))?;
+    db.pragma_update(None, "foreign_keys", false)?;
+    let applied = apply(db, &pending);
+    db.pragma_update(None, "foreign_keys", foreign_keys)?;
+    applied?;
+
+    Ok(pending)
+}
+
+fn apply(db: &mut Connection, pending: &[&'static Migration]) -> Result<(), rusqlite::Error> {
+    let tx = db.transaction()?;
+    for migration in pending {
+        info!(
+            "Applying migration {}: {}",
+            migration.version, migration.description
+        );
+        (migration.apply)(&tx)?;
+        tx.pragma_update(None, "user_version", migration.version)?;
+    }
+
+    // NOTE: with the checks off nothing stopped a rebuild from leaving rows pointing nowhere, so
+    // look before committing as sqlite's table rebuild procedure says to
+    let violations: Vec<String> = tx
+        .prepare("PRAGMA foreign_key_check")?
+        .query_map([], |row| {
+            Ok(format!(
+                "{} row {} references a missing {} row",
+                row.get::<_, String>(0)?,
+                row.get::<_, Option<i64>>(1)?.unwrap_or_default(),
+                row.get::<_, String>(2)?
+            ))
+        })?
+        .collect::<Result<_, _>>()?;
+    if !violations.is_empty() {
+        return Err(rusqlite::Error::SqliteFailure(
+            ffi::Error::new(ffi::SQLITE_CONSTRAINT_FOREIGNKEY),
+            Some(format!(
+                "Migrating would break {} foreign keys: {}",
+                violations.len(),
+                violations.iter().take(5).join(", ")
+            )),
+        ));
+    }
+    tx.commit()
+}
+
+fn backup_path(db_path: &Path, version: u32) -> PathBuf {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let mut backup = db_path.as_os_str().to_owned();
+    backup.push(format!(".v{version}-{now}.bak"));
+    PathBuf::from(backup)
+}
+
+fn has_column(tx: &Transaction, table: &str, column: &str) -> Result<bool, rusqlite::Error> {
+    tx.prepare("SELECT 1 FROM pragma_table_info(?1) WHERE name = ?2")?
+        .exists([table, column])
+}
+
+fn create_tables(tx: &Transaction) -> Result<(), rusqlite::Error> {
+    tx.execute(
+        "CREATE TABLE IF NOT EXISTS tracks (
+            id INTEGER PRIMARY KEY,
+            title TEXT,
+            artist TEXT,
+            album TEXT,
+            lengthseconds REAL,
+            playcount INTEGER,
+            path TEXT,
+            UNIQUE (title, artist, album)
+        )",
+        (),
+    )?;
+
+    tx.execute(
+        "CREATE TABLE IF NOT EXISTS history (
+            time DATETIME DEFAULT CURRENT_TIMESTAMP,
+            songid INTEGER,
+            FOREIGN KEY (songid) REFERENCES tracks(id)
+        )",
+        (),
+    )?;
+
+    Ok(())
+}
+
+fn add_history_events(tx: &Transaction) -> Result<(), rusqlite::Error> {
+    // NOTE: databases created just before migrations existed may already have these, every
+    // existing row was a play
+    if !has_column(tx, "history", "event")? {
+        tx.execute(
+            "ALTER TABLE history ADD COLUMN event TEXT NOT NULL DEFAULT 'play'",
+            (),
+        )?;
+    }
+    if !has_column(tx, "history", "position")? {
+        tx.execute("ALTER TABLE history ADD COLUMN position REAL", ())?;
+    }
+    Ok(())
+}
+
+fn track_identity(tx: &Transaction) -> Result<(), rusqlite::Error> {
+    // NOTE: sqlite can't drop a table constraint, so the table has to be rebuilt to lose the
+    // unique (title, artist, album). Ids are kept so history still lines up.
+    tx.execute_batch(
+        "CREATE TABLE tracks_new (
+            id INTEGER PRIMARY KEY,
+            title TEXT,
+            artist TEXT,
+            album TEXT,
+            lengthseconds REAL,
+            playcount INTEGER,
+            path TEXT,
+            file TEXT,
+            mbid TEXT
+        );
+        INSERT INTO tracks_new(id, title, artist, album, lengthseconds, playcount, path)
+            SELECT id, title, artist, album, lengthseconds, playcount, path FROM tracks;
+        DROP TABLE tracks;
+        ALTER TABLE tracks_new RENAME TO tracks;
+        CREATE INDEX tracks_file ON tracks(file);
+        CREATE INDEX tracks_mbid ON tracks(mbid);
+        CREATE INDEX history_songid ON history(songid);",
+    )
+}
+
+fn create_outbox(tx: &Transaction) -> Result<(), rusqlite::Error> {
+    // NOTE: listens are kept as the JSON that gets submitted, so they go out exactly as they were
+    // when heard even if the track's tags change in the meantime
+    tx.execute(
+        "CREATE TABLE outbox (
+            id INTEGER PRIMARY KEY,
+            listen TEXT NOT NULL,
+            queued_at DATETIME DEFAULT CURRENT_TIMESTAMP
+        )",
+        (),
+    )?;
+    Ok(())
+}
+
+fn create_ratings(tx: &Transaction) -> Result<(), rusqlite::Error> {
+    // NOTE: keyed on the file rather than a tracks row, since rating a track doesn't need it to
+    // have been played
+    tx.execute(
+        "CREATE TABLE ratings (
+            file TEXT PRIMARY KEY,
+            rating INTEGER,
+            loved INTEGER NOT NULL DEFAULT 0,
+            banned INTEGER NOT NULL DEFAULT 0,
+            updated DATETIME DEFAULT CURRENT_TIMESTAMP
+        )",
+        (),
+    )?;
+    Ok(())
+}
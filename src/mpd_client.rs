@@ -1,104 +1,441 @@
 use itertools::Itertools;
-use log::{debug, error, trace};
+use log::{debug, error, info, trace, warn};
 use std::env;
 use std::io::{BufReader, prelude::*};
-use std::os::unix::net::UnixStream;
+use std::net::TcpStream;
+use std::os::linux::net::SocketAddrExt;
+use std::os::unix::net::{SocketAddr, UnixStream};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 
+use crate::config::ConnectionConfig;
+use crate::mpd_response::{CommandError, MpdError, Response};
 use crate::surprise_me;
 
+const DEFAULT_MPD_PORT: u16 = 6600;
+// NOTE: a zero read timeout means "block forever", so never ask for less than this
+const MIN_IDLE_TIMEOUT: Duration = Duration::from_millis(10);
+
+/// Where surprise-me picks go in the queue.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum QueueMode {
+    /// After everything already queued
+    Append,
+    /// Instead of everything already queued
+    Replace,
+    /// Straight after the current song
+    InsertNext,
+}
+
+/// Where to reach MPD, resolved from `MPD_HOST`/`MPD_PORT` the same way libmpdclient does.
+#[derive(Debug, Clone)]
+enum MPDTarget {
+    Socket(PathBuf),
+    AbstractSocket(String),
+    Tcp(String, u16),
+}
+
+enum MPDStream {
+    Unix(UnixStream),
+    Tcp(TcpStream),
+}
+
+impl MPDStream {
+    fn try_clone(&self) -> std::io::Result<MPDStream> {
+        match self {
+            MPDStream::Unix(s) => s.try_clone().map(MPDStream::Unix),
+            MPDStream::Tcp(s) => s.try_clone().map(MPDStream::Tcp),
+        }
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> std::io::Result<()> {
+        match self {
+            MPDStream::Unix(s) => s.set_read_timeout(timeout),
+            MPDStream::Tcp(s) => s.set_read_timeout(timeout),
+        }
+    }
+}
+
+impl Read for MPDStream {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match self {
+            MPDStream::Unix(s) => s.read(buf),
+            MPDStream::Tcp(s) => s.read(buf),
+        }
+    }
+}
+
+impl Write for MPDStream {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            MPDStream::Unix(s) => s.write(buf),
+            MPDStream::Tcp(s) => s.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            MPDStream::Unix(s) => s.flush(),
+            MPDStream::Tcp(s) => s.flush(),
+        }
+    }
+}
+
 pub(crate) struct MPDClient {
-    stream: UnixStream,
-    reader: BufReader<UnixStream>,
+    stream: MPDStream,
+    reader: BufReader<MPDStream>,
+    local: bool,
+    target: MPDTarget,
+    password: Option<String>,
 }
 
 impl MPDClient {
-    pub(crate) fn connect() -> MPDClient {
+    pub(crate) fn connect(config: &ConnectionConfig) -> MPDClient {
         debug!("Initializing MPD connection");
-        // NOTE: MUST use a unix socket to manage the queue locally. This is "documented" in the mpd
-        // protocal manual here: https://mpd.readthedocs.io/en/latest/client.html#introduction
-        // where "local socket" means "unix socket".
+        let (target, password) = resolve_target(config);
+        debug!("Resolved MPD target {target:?}");
+
+        let client = MPDClient::open(target, password)
+            .unwrap_or_else(|err| panic!("Failed to connect to MPD: {err}"));
+        if !client.local {
+            info!("Connected to MPD over TCP, queue management will use library-relative paths");
+        }
+        client
+    }
+
+    /// Drop the current connection and connect to the same target again, redoing the handshake
+    /// and password.
+    pub(crate) fn reconnect(&mut self) -> Result<(), CommandError> {
+        debug!("Reconnecting to MPD at {:?}", self.target);
+        *self = MPDClient::open(self.target.clone(), self.password.clone())?;
+        Ok(())
+    }
+
+    fn open(target: MPDTarget, password: Option<String>) -> Result<MPDClient, CommandError> {
+        // NOTE: MUST use a unix socket to manage the queue with absolute paths. This is
+        // "documented" in the mpd protocal manual here:
+        // https://mpd.readthedocs.io/en/latest/client.html#introduction where "local socket"
+        // means "unix socket".
         // See also: https://github.com/MusicPlayerDaemon/MPD/issues/2184
-        let stream = UnixStream::connect(
-            env::var("XDG_RUNTIME_DIR").unwrap_or("/run".to_string()) + "/mpd/socket",
-        )
-        .unwrap_or_else(|err| panic!("Failed to connect to MPD socket: {err:?}"));
-
-        let mut reader = BufReader::new(stream.try_clone().expect("MPD connection invalid"));
-        let recv: Vec<u8> = reader
-            .fill_buf()
-            .expect("MPD connection returned initial handshake")
-            .to_vec();
-        reader.consume(recv.len());
-        let connect_ack = String::from_utf8(recv).expect("MPD connection handshake readable");
+        let (stream, local) = match &target {
+            MPDTarget::Socket(path) => (MPDStream::Unix(UnixStream::connect(path)?), true),
+            MPDTarget::AbstractSocket(name) => {
+                let addr = SocketAddr::from_abstract_name(name.as_bytes())?;
+                (MPDStream::Unix(UnixStream::connect_addr(&addr)?), true)
+            }
+            MPDTarget::Tcp(host, port) => (
+                MPDStream::Tcp(TcpStream::connect((host.as_str(), *port))?),
+                false,
+            ),
+        };
+
+        let mut reader = BufReader::new(stream.try_clone()?);
+        let mut connect_ack = String::new();
+        reader.read_line(&mut connect_ack)?;
 
         // NOTE: Protocol version agnostic here, see:
         // https://mpd.readthedocs.io/en/latest/protocol.html#protocol-overview
-        if !connect_ack.contains("OK MPD") {
-            panic!("Unknown connection string: {connect_ack}")
+        if !connect_ack.starts_with("OK MPD") {
+            return Err(CommandError::Io(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("Unknown connection string: {}", connect_ack.trim()),
+            )));
         }
 
-        MPDClient { stream, reader }
+        let mut client = MPDClient {
+            stream,
+            reader,
+            local,
+            target,
+            password,
+        };
+
+        if let Some(password) = client.password.clone() {
+            debug!("Sending MPD password");
+            client.send_command(format!("password \"{}\"\n", escape(&password)))?;
+        }
+
+        Ok(client)
     }
 
-    pub(crate) fn add_to_queue(&mut self, tracks: &[surprise_me::SelectedTrack]) {
-        debug!("Adding {} tracks to queue", tracks.len());
+    /// Whether this connection is over a unix socket, which MPD treats as "local" and so allows
+    /// commands like `config` and adding songs by absolute path.
+    pub(crate) fn is_local(&self) -> bool {
+        self.local
+    }
+
+    /// The URIs to send MPD for some tracks. A local client can use absolute paths, but MPD refuses
+    /// them from a remote one and never takes them in stored playlists, so then the music directory
+    /// is stripped back off where it can be, and anything that can't be made relative is skipped.
+    fn uris(
+        &self,
+        tracks: &[surprise_me::SelectedTrack],
+        music_dir: &Path,
+        relative: bool,
+    ) -> Vec<String> {
+        if self.local && !relative {
+            return tracks.iter().map(|t| t.path.clone()).collect();
+        }
+        tracks
+            .iter()
+            .filter_map(|t| {
+                let path = Path::new(&t.path);
+                match path.strip_prefix(music_dir) {
+                    Ok(relative) if !music_dir.as_os_str().is_empty() => {
+                        Some(relative.to_str().unwrap().to_string())
+                    }
+                    _ if path.is_relative() => Some(t.path.clone()),
+                    _ => {
+                        warn!("Cannot add {} by a relative path, skipping", t.path);
+                        None
+                    }
+                }
+            })
+            .collect()
+    }
+
+    pub(crate) fn add_to_queue(
+        &mut self,
+        tracks: &[surprise_me::SelectedTrack],
+        music_dir: &Path,
+        mode: QueueMode,
+    ) {
+        debug!("Adding {} tracks to queue with {mode:?}", tracks.len());
         trace!("Adding {}", tracks.iter().map(|t| t.path.clone()).join(","));
+        let uris = self.uris(tracks, music_dir, false);
+        if uris.is_empty() {
+            warn!("No tracks could be added, leaving the queue as it is");
+            return;
+        }
+
+        // NOTE: positions relative to the current song only work when there is one
+        let mode = if mode == QueueMode::InsertNext
+            && self
+                .send_command("status\n".to_string())
+                .map(|status| status.get("song").is_none())
+                .unwrap_or(true)
+        {
+            warn!("Nothing is playing to insert after, adding to the end of the queue instead");
+            QueueMode::Append
+        } else {
+            mode
+        };
+
         let command = "command_list_begin\n".to_owned()
-            + &tracks
+            + if mode == QueueMode::Replace {
+                "clear\n"
+            } else {
+                ""
+            }
+            + &uris
                 .iter()
-                .map(|t| "add \"".to_string() + &t.path + "\"")
+                .enumerate()
+                .map(|(i, uri)| match mode {
+                    // each one after the last, rather than all straight after the current song
+                    QueueMode::InsertNext => format!("add \"{}\" \"+{i}\"", escape(uri)),
+                    _ => "add \"".to_string() + &escape(uri) + "\"",
+                })
                 .join("\n")
             + "\n"
             + "status\n"
             + "command_list_end\n";
-        match self.send_command(command) {
-            Some(status) => {
-                // Decide what to do based on player state after adding to the queue
-                // nothing in queue and eurydice is run: state == stop -> send play
-                // something is playing and eurydice is run: state == play -> do nothing
-                // something is paused and eurydice is run: state == pause -> do nothing
-                // TODO: heavy handed split sequence, I could use a regex split to a hashmap for the rest of
-                // the status info, but don't need it right now
-                let player_state = status
-                    .split_once("state: ")
-                    .unwrap()
-                    .1
-                    .split_once("\n")
-                    .unwrap()
-                    .0;
-                debug!("Player in state {player_state}");
-
-                if player_state == "stop" {
-                    self.send_command("play 0\n".to_string());
-                }
-            }
-            None => panic!("MPD status returned no information, cannot manage queue"),
+        let status = self
+            .send_command(command)
+            .unwrap_or_else(|err| panic!("MPD could not add tracks to the queue: {err}"));
+        // Decide what to do based on player state after adding to the queue
+        // nothing in queue and eurydice is run: state == stop -> send play
+        // something is playing and eurydice is run: state == play -> do nothing
+        // something is paused and eurydice is run: state == pause -> do nothing
+        // the queue was replaced: the picks are all that's there -> send play
+        let player_state = status
+            .get("state")
+            .expect("MPD status returned no player state, cannot manage queue");
+        debug!("Player in state {player_state}");
+
+        if (player_state == "stop" || mode == QueueMode::Replace)
+            && let Err(err) = self.send_command("play 0\n".to_string())
+        {
+            error!("Could not start playback: {err}");
         }
     }
 
-    pub(crate) fn send_command(&mut self, command: String) -> Option<String> {
+    /// Write tracks to a stored playlist, replacing whatever was in it before.
+    pub(crate) fn save_playlist(
+        &mut self,
+        name: &str,
+        tracks: &[surprise_me::SelectedTrack],
+        music_dir: &Path,
+    ) {
+        debug!("Saving {} tracks to playlist {name}", tracks.len());
+        let uris = self.uris(tracks, music_dir, true);
+        if uris.is_empty() {
+            warn!("No tracks could be saved, leaving playlist {name} as it is");
+            return;
+        }
+        // NOTE: clearing a playlist that doesn't exist yet is an error, and would abort the list
+        let exists = self
+            .send_command("listplaylists\n".to_string())
+            .expect("MPD could not list playlists")
+            .get_all("playlist")
+            .any(|p| p == name);
+        if exists {
+            info!("Replacing the contents of playlist {name}");
+        }
+
+        let name = escape(name);
+        let command = "command_list_begin\n".to_owned()
+            + &if exists {
+                format!("playlistclear \"{name}\"\n")
+            } else {
+                String::new()
+            }
+            + &uris
+                .iter()
+                .map(|uri| format!("playlistadd \"{name}\" \"{}\"", escape(uri)))
+                .join("\n")
+            + "\n"
+            + "command_list_end\n";
+        self.send_command(command)
+            .unwrap_or_else(|err| panic!("MPD could not save playlist {name}: {err}"));
+    }
+
+    /// Whether a song URI is (still) in the MPD library.
+    pub(crate) fn has_song(&mut self, uri: &str) -> Result<bool, CommandError> {
+        let filter = format!("(file == {})", filter_value(uri));
+        self.send_command(format!("find \"{}\"\n", escape(&filter)))
+            .map(|found| found.get("file").is_some())
+    }
+
+    /// Every song in the MPD library, one record each.
+    pub(crate) fn library(&mut self) -> Result<Vec<Response>, CommandError> {
+        self.send_command("find \"(file != '')\"\n".to_string())
+            .map(|songs| songs.records("file"))
+    }
+
+    pub(crate) fn send_command(&mut self, command: String) -> Result<Response, CommandError> {
         debug!("Sending MPD command {}", command.trim());
-        self.stream.write_all(command.as_bytes()).ok()?;
-        self.stream.flush().ok()?;
+        self.stream.write_all(command.as_bytes())?;
+        self.stream.flush()?;
+        self.read_response(command.trim())
+    }
+
+    /// Wait for a change in one of the given subsystems (e.g. `player`). With a timeout, give up
+    /// waiting once it elapses by sending `noidle`, which MPD answers with an empty (or, if we
+    /// raced a change, populated) response.
+    pub(crate) fn idle(
+        &mut self,
+        subsystems: &str,
+        timeout: Option<Duration>,
+    ) -> Result<Response, CommandError> {
+        let command = format!("idle {subsystems}");
+        debug!("Sending MPD command {command} with timeout {timeout:?}");
+        self.stream.write_all((command.clone() + "\n").as_bytes())?;
+        self.stream.flush()?;
 
-        let mut full_msg = String::new();
+        if let Some(timeout) = timeout {
+            // NOTE: read timeouts are set on the socket itself, so this applies to the reader's
+            // clone of the stream too
+            self.stream
+                .set_read_timeout(Some(timeout.max(MIN_IDLE_TIMEOUT)))?;
+            let waited = self.reader.fill_buf().map(|buf| buf.len());
+            self.stream.set_read_timeout(None)?;
+            match waited {
+                Ok(_) => {}
+                Err(err)
+                    if matches!(
+                        err.kind(),
+                        std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut
+                    ) =>
+                {
+                    trace!("Idle timed out after {timeout:?}, cancelling");
+                    self.stream.write_all(b"noidle\n")?;
+                    self.stream.flush()?;
+                }
+                Err(err) => return Err(err.into()),
+            }
+        }
+
+        self.read_response(&command)
+    }
+
+    fn read_response(&mut self, command: &str) -> Result<Response, CommandError> {
+        let mut response = Response::default();
         loop {
             let mut curr_line = String::new();
-            _ = self.reader.read_line(&mut curr_line);
-            match curr_line {
-                val if val == "OK\n" => {
-                    trace!("Response for {command} was OK");
-                    break;
-                }
-                // FIXME: regex for proper ACK [error@command_listNum] otherwise this matches
-                // things with the word BLACK in all caps for example
-                val if val.ends_with("ACK [") => {
-                    error!("Failed response for {command}: {val}");
-                    break;
-                }
-                val => full_msg += &val,
-            };
+            if self.reader.read_line(&mut curr_line)? == 0 {
+                return Err(CommandError::Io(std::io::Error::new(
+                    std::io::ErrorKind::UnexpectedEof,
+                    "MPD closed the connection",
+                )));
+            }
+            if curr_line == "OK\n" {
+                trace!("Response for {command} was OK");
+                return Ok(response);
+            }
+            if let Some(err) = MpdError::parse(&curr_line) {
+                error!("Failed response for {command}: {err}");
+                return Err(CommandError::Ack(err));
+            }
+            response.push_line(&curr_line);
         }
-        Some(full_msg)
     }
 }
+
+/// Resolve the MPD connection target and optional password from the environment, following the
+/// libmpdclient conventions:
+/// * `MPD_HOST=/path/to/socket` for a unix socket
+/// * `MPD_HOST=@name` for an abstract socket
+/// * `MPD_HOST=host` (with `MPD_PORT`, default 6600) for TCP
+/// * any of the above prefixed with `password@`
+///
+/// The `[connection]` section of the config fills in for either variable when it isn't set, and
+/// without a host at all this falls back to `$XDG_RUNTIME_DIR/mpd/socket`.
+fn resolve_target(config: &ConnectionConfig) -> (MPDTarget, Option<String>) {
+    let Some(mpd_host) = env::var("MPD_HOST").ok().or(config.host.clone()) else {
+        return (
+            MPDTarget::Socket(PathBuf::from(
+                env::var("XDG_RUNTIME_DIR").unwrap_or("/run".to_string()) + "/mpd/socket",
+            )),
+            config.password.clone(),
+        );
+    };
+
+    // NOTE: a leading '@' is an abstract socket name, not an empty password
+    let (password, host) = match mpd_host.split_once('@') {
+        Some((password, host)) if !password.is_empty() => {
+            (Some(password.to_string()), host.to_string())
+        }
+        _ => (config.password.clone(), mpd_host),
+    };
+
+    let target = if let Some(name) = host.strip_prefix('@') {
+        MPDTarget::AbstractSocket(name.to_string())
+    } else if host.starts_with('/') {
+        MPDTarget::Socket(PathBuf::from(host))
+    } else {
+        let port = match env::var("MPD_PORT").map(|p| p.parse::<u16>()) {
+            Ok(Ok(port)) => port,
+            Ok(Err(err)) => {
+                warn!("Invalid MPD_PORT, using {DEFAULT_MPD_PORT}: {err:?}");
+                DEFAULT_MPD_PORT
+            }
+            Err(_) => config.port.unwrap_or(DEFAULT_MPD_PORT),
+        };
+        MPDTarget::Tcp(host, port)
+    };
+
+    (target, password)
+}
+
+/// Escape a command argument for use inside double quotes, per
+/// https://mpd.readthedocs.io/en/latest/protocol.html#escaping-string-values
+pub(crate) fn escape(arg: &str) -> String {
+    arg.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+/// Quote a value for use in a filter expression such as `(Artist == 'value')`, per
+/// https://mpd.readthedocs.io/en/latest/protocol.html#filters
+/// The whole expression still needs [`escape`] when sent as a command argument.
+pub(crate) fn filter_value(value: &str) -> String {
+    format!("'{}'", value.replace('\\', "\\\\").replace('\'', "\\'"))
+}
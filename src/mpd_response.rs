@@ -0,0 +1,162 @@
+use log::warn;
+use std::fmt;
+
+/// An `ACK` error line returned by MPD, in the form
+/// `ACK [error@command_listNum] {current_command} message_text`.
+/// See https://mpd.readthedocs.io/en/latest/protocol.html#failure-responses
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct MpdError {
+    pub(crate) code: u32,
+    pub(crate) command_list_num: u32,
+    pub(crate) command: String,
+    pub(crate) message: String,
+}
+
+impl MpdError {
+    /// Parse an `ACK` line, returning `None` if the line is not an error response.
+    pub(crate) fn parse(line: &str) -> Option<MpdError> {
+        let rest = line.trim_end().strip_prefix("ACK [")?;
+        let (code, rest) = rest.split_once('@')?;
+        let (command_list_num, rest) = rest.split_once("] {")?;
+        let (command, message) = rest.split_once('}')?;
+        Some(MpdError {
+            code: code.parse().ok()?,
+            command_list_num: command_list_num.parse().ok()?,
+            command: command.to_string(),
+            message: message.trim_start().to_string(),
+        })
+    }
+}
+
+impl fmt::Display for MpdError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "MPD error {} in command {} ({}): {}",
+            self.code, self.command_list_num, self.command, self.message
+        )
+    }
+}
+
+/// Anything that can go wrong sending a command to MPD.
+#[derive(Debug)]
+pub(crate) enum CommandError {
+    Io(std::io::Error),
+    Ack(MpdError),
+}
+
+impl fmt::Display for CommandError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CommandError::Io(err) => write!(f, "MPD connection error: {err}"),
+            CommandError::Ack(err) => err.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for CommandError {}
+
+impl From<std::io::Error> for CommandError {
+    fn from(err: std::io::Error) -> Self {
+        CommandError::Io(err)
+    }
+}
+
+/// The key/value pairs of a successful MPD response, in the order they were sent.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub(crate) struct Response {
+    pairs: Vec<(String, String)>,
+}
+
+impl Response {
+    /// Add a single `key: value` response line.
+    pub(crate) fn push_line(&mut self, line: &str) {
+        match line.trim_end_matches('\n').split_once(": ") {
+            Some((key, value)) => self.pairs.push((key.to_string(), value.to_string())),
+            None => warn!("Skipping unparseable MPD response line: {line}"),
+        }
+    }
+
+    pub(crate) fn pairs(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.pairs.iter().map(|(k, v)| (k.as_str(), v.as_str()))
+    }
+
+    /// The first value for `key`. Keys are compared case-sensitively, as MPD sends them.
+    pub(crate) fn get(&self, key: &str) -> Option<&str> {
+        self.pairs().find(|(k, _)| *k == key).map(|(_, v)| v)
+    }
+
+    /// Every value for `key`, for tags that can repeat such as `Artist` or `Genre`.
+    pub(crate) fn get_all<'a>(&'a self, key: &'a str) -> impl Iterator<Item = &'a str> {
+        self.pairs().filter(move |(k, _)| *k == key).map(|(_, v)| v)
+    }
+
+    /// Split a response into records, each starting at a `start_key` line. This is how MPD
+    /// returns lists of songs (`file`), playlists (`playlist`), outputs (`outputid`) and so on.
+    /// Anything before the first `start_key` is dropped.
+    pub(crate) fn records(&self, start_key: &str) -> Vec<Response> {
+        let mut records = Vec::<Response>::new();
+        for (key, value) in &self.pairs {
+            if key == start_key {
+                records.push(Response::default());
+            }
+            if let Some(record) = records.last_mut() {
+                record.pairs.push((key.clone(), value.clone()));
+            }
+        }
+        records
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn response(lines: &[&str]) -> Response {
+        let mut response = Response::default();
+        lines.iter().for_each(|line| response.push_line(line));
+        response
+    }
+
+    #[test]
+    fn ack_in_value_is_not_an_error() {
+        let line = "Title: ACK [50@0] {find} a song named like an error\n";
+        assert_eq!(MpdError::parse(line), None);
+        assert_eq!(
+            response(&[line]).get("Title"),
+            Some("ACK [50@0] {find} a song named like an error")
+        );
+    }
+
+    #[test]
+    fn ack_with_empty_command() {
+        assert_eq!(
+            MpdError::parse("ACK [5@0] {} unknown command \"foo\"\n"),
+            Some(MpdError {
+                code: 5,
+                command_list_num: 0,
+                command: String::new(),
+                message: "unknown command \"foo\"".to_string(),
+            })
+        );
+    }
+
+    #[test]
+    fn records_drop_anything_before_the_first_start_key() {
+        let records = response(&[
+            "directory: Band",
+            "Last-Modified: 2026-01-01T00:00:00Z",
+            "file: Band/01 intro.flac",
+            "Title: Intro",
+            "file: Band/02 song.flac",
+        ])
+        .records("file");
+        assert_eq!(
+            records,
+            [
+                response(&["file: Band/01 intro.flac", "Title: Intro"]),
+                response(&["file: Band/02 song.flac"]),
+            ]
+        );
+    }
+}
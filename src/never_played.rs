@@ -4,21 +4,23 @@ use std::path::Path;
 use itertools::Itertools;
 use rusqlite::{Connection, fallible_iterator::FallibleIterator};
 
-use crate::{collection::build_collection_maps, mpd_client::MPDClient};
+use crate::{collection::build_collection_maps, ignore::IgnoreRules, mpd_client::MPDClient};
 
 pub(crate) fn never_played(
     db: &Connection,
     client: &mut MPDClient,
     music_dir: &Path,
+    ignore: &IgnoreRules,
 ) -> Result<String, rusqlite::Error> {
-    let query = "select title || ' - ' || artist from tracks".to_string();
+    // NOTE: skipped tracks get a row too, they just haven't been played
+    let query = "select title || ' - ' || artist from tracks where playcount > 0".to_string();
     let played_tracks: HashSet<String> = db
         .prepare(&query)?
         .query([])?
         .map(|r| r.get(0))
         .collect()
         .unwrap();
-    let (all_tracks, _) = build_collection_maps(client, music_dir);
+    let (all_tracks, _) = build_collection_maps(client, music_dir, ignore);
     // PERF: this clone is unnecessary but this command is a one off
     let all_tracks_set: HashSet<String> = all_tracks
         .iter()
@@ -0,0 +1,163 @@
+use log::{debug, warn};
+use rusqlite::Connection;
+use std::collections::HashMap;
+use std::path::Path;
+
+use crate::mpd_client::{MPDClient, escape};
+
+// NOTE: the same sticker myMPD uses, which counts out of 10 to allow half stars
+const RATING_STICKER: &str = "rating";
+
+/// How a track has been rated, if at all.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub(crate) struct Rating {
+    /// 1 to 5 stars
+    pub(crate) stars: Option<u8>,
+    pub(crate) loved: bool,
+    /// Never picked by surprise-me
+    pub(crate) banned: bool,
+}
+
+impl Rating {
+    /// How much more likely this track is to be picked than an unrated one heard just as often:
+    /// double for five stars down to half for one, with three stars counting the same as
+    /// unrated. Loving a track doubles it again.
+    pub(crate) fn preference(&self) -> f64 {
+        let stars = self
+            .stars
+            .map(|stars| 2_f64.powf((stars as f64 - 3.0) / 2.0))
+            .unwrap_or(1.0);
+        if self.loved { stars * 2.0 } else { stars }
+    }
+
+    pub(crate) fn describe(&self) -> String {
+        let stars = self
+            .stars
+            .map(|stars| "★".repeat(stars as usize) + &"☆".repeat(5 - stars as usize))
+            .unwrap_or_default();
+        match (self.loved, self.banned) {
+            (_, true) => stars + " banned",
+            (true, _) => stars + " ♥",
+            _ => stars,
+        }
+        .trim()
+        .to_string()
+    }
+}
+
+/// A change to one track's rating.
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum Change {
+    /// `None` clears it
+    Stars(Option<u8>),
+    Loved(bool),
+    Banned(bool),
+}
+
+impl Change {
+    pub(crate) fn describe(&self) -> String {
+        match self {
+            Change::Stars(Some(stars)) => format!("Rated {stars}/5:"),
+            Change::Stars(None) => "Cleared the rating of".to_string(),
+            Change::Loved(true) => "Loved".to_string(),
+            Change::Loved(false) => "Unloved".to_string(),
+            Change::Banned(true) => "Banned".to_string(),
+            Change::Banned(false) => "Unbanned".to_string(),
+        }
+    }
+}
+
+/// Every rated track, keyed by file.
+pub(crate) fn all(db: &Connection) -> Result<HashMap<String, Rating>, rusqlite::Error> {
+    db.prepare("SELECT file, rating, loved, banned FROM ratings")?
+        .query_map([], |row| {
+            Ok((
+                row.get(0)?,
+                Rating {
+                    stars: row.get(1)?,
+                    loved: row.get(2)?,
+                    banned: row.get(3)?,
+                },
+            ))
+        })?
+        .collect()
+}
+
+/// The library-relative URI of the song to rate: the one given, as a URI or a path under the
+/// music directory, or whatever is playing.
+fn target_file(
+    client: &mut MPDClient,
+    music_dir: &Path,
+    file: Option<String>,
+) -> Result<String, String> {
+    let Some(file) = file else {
+        return client
+            .send_command("currentsong\n".to_string())
+            .map_err(|err| err.to_string())?
+            .get("file")
+            .map(|f| f.to_string())
+            .ok_or("Nothing is playing, give a file to rate instead".to_string());
+    };
+    let path = Path::new(&file);
+    let uri = if path.is_absolute() {
+        path.strip_prefix(music_dir)
+            .ok()
+            .filter(|_| !music_dir.as_os_str().is_empty())
+            .and_then(|p| p.to_str())
+            .ok_or(format!("{file} is not in the music directory"))?
+            .to_string()
+    } else {
+        file.clone()
+    };
+    let found = client
+        .send_command(format!("lsinfo \"{}\"\n", escape(&uri)))
+        .is_ok_and(|info| info.get("file").is_some());
+    if !found {
+        return Err(format!("{uri} is not a song in the MPD library"));
+    }
+    Ok(uri)
+}
+
+/// Change the rating of a song, mirroring stars to its `rating` sticker. Returns the song's URI.
+pub(crate) fn apply(
+    db: &Connection,
+    client: &mut MPDClient,
+    music_dir: &Path,
+    file: Option<String>,
+    change: Change,
+) -> Result<String, String> {
+    let file = target_file(client, music_dir, file)?;
+    debug!("Applying {change:?} to {file}");
+    let (column, value) = match change {
+        Change::Stars(stars) => ("rating", stars.map(|s| s as i64)),
+        Change::Loved(loved) => ("loved", Some(loved as i64)),
+        Change::Banned(banned) => ("banned", Some(banned as i64)),
+    };
+    db.execute(
+        &format!(
+            "INSERT INTO ratings(file, {column}) VALUES (?1, ?2)
+            ON CONFLICT(file) DO UPDATE SET {column} = excluded.{column},
+                updated = CURRENT_TIMESTAMP"
+        ),
+        (&file, value),
+    )
+    .map_err(|err| format!("Could not save rating: {err:?}"))?;
+
+    if let Change::Stars(stars) = change {
+        let uri = escape(&file);
+        let command = match stars {
+            Some(stars) => {
+                format!(
+                    "sticker set song \"{uri}\" \"{RATING_STICKER}\" \"{}\"\n",
+                    stars * 2
+                )
+            }
+            None => format!("sticker delete song \"{uri}\" \"{RATING_STICKER}\"\n"),
+        };
+        // NOTE: the rating is saved either way, the sticker is only for other clients
+        if let Err(err) = client.send_command(command) {
+            warn!("Could not write the rating sticker for {file}: {err}");
+        }
+    }
+    Ok(file)
+}
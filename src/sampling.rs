@@ -0,0 +1,123 @@
+use clap::ValueEnum;
+use itertools::Itertools;
+use log::debug;
+use rand::rngs::StdRng;
+use rand::{RngExt, SeedableRng};
+use serde::{Deserialize, Serialize};
+
+/// Days since a track was last heard at which the recency-decay weighting has it at half the
+/// chance of a never heard track with the same count.
+const RECENCY_HALF_LIFE_DAYS: f64 = 30.0;
+
+/// How the chance of picking something falls off the more (and the more lately) it's been heard.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum Weighting {
+    /// Proportional to 1 / (1 + plays and skips), scaled by how it's rated
+    InverseCount,
+    /// Inverse count, scaled further down the more recently it was last heard
+    RecencyDecay,
+    /// Every candidate equally likely, however it's rated
+    Random,
+}
+
+impl Weighting {
+    fn weight(&self, heard: f64, days_since_heard: Option<f64>, preference: f64) -> f64 {
+        let inverse_count = preference / (1.0 + heard.max(0.0));
+        match self {
+            Weighting::InverseCount => inverse_count,
+            Weighting::RecencyDecay => {
+                // never heard at all counts as fully recovered
+                let recovered = days_since_heard
+                    .map(|days| 1.0 - 0.5_f64.powf(days.max(0.0) / RECENCY_HALF_LIFE_DAYS))
+                    .unwrap_or(1.0);
+                inverse_count * recovered
+            }
+            Weighting::Random => 1.0,
+        }
+    }
+}
+
+/// Something that could be picked, with how often and how long ago it was last heard, and how
+/// much more it's liked than usual (1.0 for no preference either way).
+pub(crate) struct Candidate<T> {
+    pub(crate) item: T,
+    pub(crate) heard: f64,
+    pub(crate) days_since_heard: Option<f64>,
+    pub(crate) preference: f64,
+}
+
+/// Weighted random picks, reproducible when given a seed.
+pub(crate) struct Sampler {
+    weighting: Weighting,
+    rng: StdRng,
+}
+
+impl Sampler {
+    pub(crate) fn new(weighting: Weighting, seed: Option<u64>) -> Sampler {
+        debug!("Sampling with {weighting:?} weighting and seed {seed:?}");
+        Sampler {
+            weighting,
+            rng: match seed {
+                Some(seed) => StdRng::seed_from_u64(seed),
+                None => rand::make_rng(),
+            },
+        }
+    }
+
+    pub(crate) fn weight(&self, heard: f64, days_since_heard: Option<f64>, preference: f64) -> f64 {
+        self.weighting.weight(heard, days_since_heard, preference)
+    }
+
+    /// Put the candidates in a weighted random order, so taking from the front is weighted
+    /// sampling without replacement. Uses the Efraimidis-Spirakis keys u^(1/w), compared as
+    /// ln(u)/w to stay clear of underflow with tiny weights. Zero weights always end up last.
+    pub(crate) fn shuffle<T>(&mut self, candidates: Vec<Candidate<T>>) -> Vec<T> {
+        candidates
+            .into_iter()
+            .map(|c| {
+                let weight = self.weight(c.heard, c.days_since_heard, c.preference);
+                let u: f64 = self.rng.random();
+                let key = if weight > 0.0 {
+                    u.ln() / weight
+                } else {
+                    f64::NEG_INFINITY
+                };
+                (key, c.item)
+            })
+            .sorted_by(|a, b| b.0.total_cmp(&a.0))
+            .map(|(_, item)| item)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn candidates() -> Vec<Candidate<usize>> {
+        (0..20)
+            .map(|i| Candidate {
+                item: i,
+                heard: (i % 4) as f64,
+                days_since_heard: Some(i as f64),
+                preference: 1.0,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn same_seed_same_order() {
+        let order = |seed| Sampler::new(Weighting::RecencyDecay, Some(seed)).shuffle(candidates());
+        assert_eq!(order(42), order(42));
+        assert_ne!(order(42), order(43));
+    }
+
+    #[test]
+    fn zero_weights_last() {
+        let mut candidates = candidates();
+        candidates[0].days_since_heard = Some(0.0);
+        let order = Sampler::new(Weighting::RecencyDecay, Some(1)).shuffle(candidates);
+        assert_eq!(order.last(), Some(&0));
+    }
+}
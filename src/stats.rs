@@ -1,247 +1,417 @@
+use clap::ValueEnum;
 use colored::Colorize;
 use itertools::Itertools;
 use log::error;
-use rusqlite::Connection;
+use rusqlite::{Connection, OptionalExtension};
+use serde::{Deserialize, Serialize};
 use tabled::{builder::Builder, settings::Style};
 
-fn total_playtime(db: &Connection) -> Result<std::time::Duration, rusqlite::Error> {
-    let query =
-        "select sum(lengthseconds) from tracks inner join history on tracks.id = history.songid"
-            .to_string();
-    Ok(db
-        .prepare(&query)?
-        .query_map([], |row| {
-            Ok(std::time::Duration::from_secs_f64(row.get(0)?))
-        })?
-        .flatten()
-        .last()
-        .unwrap_or(std::time::Duration::new(0, 0)))
+#[derive(Debug, Clone)]
+// for clap
+#[derive(Copy, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
+// for the config file
+#[derive(Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum StatsFormat {
+    Table,
+    Json,
+    Csv,
+}
+
+/// Named shortcuts for `--since`, counting back from now.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub(crate) enum StatsPeriod {
+    Week,
+    Month,
+    Year,
+}
+
+/// The span of play history to compute stats over. Both bounds are normalized sqlite datetimes
+/// (`YYYY-MM-DD HH:MM:SS`, UTC like `history.time`), `until` being exclusive.
+#[derive(Debug, Clone)]
+pub(crate) struct TimeWindow {
+    pub(crate) since: Option<String>,
+    pub(crate) until: Option<String>,
+}
+
+/// Restricts a query joined on `history` to the window, with the bounds bound as `?1` and `?2`.
+// NOTE: the open ended bounds have to stay text, a bare '9999' gets numeric affinity from the
+// DATETIME column and then compares below every text timestamp
+pub(crate) const IN_WINDOW: &str =
+    "history.time >= coalesce(?1, '') AND history.time < coalesce(?2, '9999-12-31')";
+
+impl TimeWindow {
+    /// Build a window from the `stats` arguments. Dates are anything sqlite understands, and an
+    /// `until` given as a bare date includes that whole day.
+    pub(crate) fn new(
+        db: &Connection,
+        since: Option<String>,
+        until: Option<String>,
+        period: Option<StatsPeriod>,
+    ) -> Result<TimeWindow, String> {
+        let normalize = |date: &str, modifier: &str| -> Result<String, String> {
+            db.query_row("SELECT datetime(?1, ?2)", [date, modifier], |row| {
+                row.get::<_, Option<String>>(0)
+            })
+            .optional()
+            .map_err(|err| format!("{err:?}"))?
+            .flatten()
+            .ok_or_else(|| format!("Could not understand date \"{date}\""))
+        };
+
+        let since = match (since, period) {
+            (Some(since), _) => Some(normalize(&since, "+0 seconds")?),
+            (None, Some(period)) => Some(normalize(
+                "now",
+                match period {
+                    StatsPeriod::Week => "-7 days",
+                    StatsPeriod::Month => "-1 months",
+                    StatsPeriod::Year => "-1 years",
+                },
+            )?),
+            (None, None) => None,
+        };
+        let until = match until {
+            // NOTE: a bare YYYY-MM-DD means the end of that day, not the start
+            Some(until) if until.len() == 10 => Some(normalize(&until, "+1 day")?),
+            Some(until) => Some(normalize(&until, "+0 seconds")?),
+            None => None,
+        };
+        Ok(TimeWindow { since, until })
+    }
+
+    /// A window covering a single calendar year.
+    pub(crate) fn year(year: i32) -> TimeWindow {
+        TimeWindow {
+            since: Some(format!("{year:04}-01-01 00:00:00")),
+            until: Some(format!("{:04}-01-01 00:00:00", year + 1)),
+        }
+    }
+
+    fn describe(&self) -> Option<String> {
+        match (&self.since, &self.until) {
+            (None, None) => None,
+            (since, until) => Some(format!(
+                "{} to {}",
+                since.as_deref().unwrap_or("the beginning"),
+                until.as_deref().unwrap_or("now")
+            )),
+        }
+    }
+}
+
+/// Everything `eurydice stats` reports, independent of how it gets printed. Anything that failed
+/// to compute is left empty (and logged) rather than failing the whole report.
+#[derive(Debug, Serialize)]
+pub(crate) struct StatsReport {
+    pub(crate) since: Option<String>,
+    pub(crate) until: Option<String>,
+    pub(crate) total_playtime_seconds: Option<u64>,
+    pub(crate) total_listens: Option<i64>,
+    pub(crate) unique_tracks: Option<i64>,
+    pub(crate) albums: Option<i64>,
+    pub(crate) artists: Option<i64>,
+    pub(crate) top_tracks: Vec<RankedItem>,
+    pub(crate) top_albums: Vec<RankedItem>,
+    pub(crate) top_artists: Vec<RankedItem>,
+    pub(crate) most_skipped: Vec<RankedItem>,
+}
+
+/// A row of one of the "most played" style lists. Album and title are left out for the lists
+/// that group above them.
+#[derive(Debug, Serialize)]
+pub(crate) struct RankedItem {
+    pub(crate) artist: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) album: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) title: Option<String>,
+    pub(crate) count: i64,
+}
+
+fn total_playtime(db: &Connection, window: &TimeWindow) -> Result<u64, rusqlite::Error> {
+    let query = format!(
+        "select coalesce(sum(lengthseconds), 0.0) from tracks inner join history on tracks.id = history.songid
+        where history.event = 'play' and {IN_WINDOW}"
+    );
+    db.query_row(&query, [&window.since, &window.until], |row| {
+        row.get::<_, f64>(0).map(|secs| secs as u64)
+    })
 }
 
-fn most_played_track(
+fn ranked(
     db: &Connection,
+    query: &str,
     limit: u32,
-    since: Option<String>,
-) -> Result<String, rusqlite::Error> {
-    let query = match since {
-        None => "select artist,album,title,playcount from tracks order by playcount desc limit ?1"
-            .to_string(),
-        Some(_boundary) => todo!(),
-    };
+    window: &TimeWindow,
+) -> Result<Vec<RankedItem>, rusqlite::Error> {
     Ok(db
-        .prepare(&query)?
-        .query_map([limit], |row| {
-            Ok(format!(
-                "{} - {} - {}: {}",
-                row.get(0)
-                    .unwrap_or("Unknown Artist".to_string())
-                    .italic()
-                    .red(),
-                row.get(1)
-                    .unwrap_or("Unknown Album".to_string())
-                    .italic()
-                    .blue(),
-                row.get(2)
-                    .unwrap_or("No Title".to_string())
-                    .italic()
-                    .purple(),
-                row.get(3).unwrap_or(0).to_string().bold().green()
-            ))
+        .prepare(query)?
+        .query_map((&window.since, &window.until, limit), |row| {
+            Ok(RankedItem {
+                artist: row
+                    .get::<_, Option<String>>("artist")?
+                    .unwrap_or("Unknown Artist".to_string()),
+                album: row
+                    .get::<_, Option<String>>("album")
+                    .ok()
+                    .map(|a| a.unwrap_or("Unknown Album".to_string())),
+                title: row
+                    .get::<_, Option<String>>("title")
+                    .ok()
+                    .map(|t| t.unwrap_or("No Title".to_string())),
+                count: row.get("count")?,
+            })
         })?
         .flatten()
-        .join("\n"))
+        .collect())
 }
 
-fn most_played_albums(db: &Connection, limit: u32) -> Result<String, rusqlite::Error> {
-    let query = "select artist,album,sum(playcount) as p from tracks group by album order by p desc limit ?1".to_string();
-    Ok(db
-        .prepare(&query)?
-        .query_map([limit], |row| {
-            Ok(format!(
-                "{} - {}: {}",
-                row.get(0)
-                    .unwrap_or("Unknown Artist".to_string())
-                    .italic()
-                    .red(),
-                row.get(1)
-                    .unwrap_or("Unknown Album".to_string())
-                    .italic()
-                    .blue(),
-                row.get(2).unwrap_or(0).to_string().bold().green()
-            ))
-        })?
-        .flatten()
-        .join("\n"))
+fn most_played_tracks(
+    db: &Connection,
+    limit: u32,
+    window: &TimeWindow,
+) -> Result<Vec<RankedItem>, rusqlite::Error> {
+    let query = format!(
+        "select artist,album,title,count(*) as count from tracks inner join history on tracks.id = history.songid
+        where history.event = 'play' and {IN_WINDOW} group by tracks.id order by count desc limit ?3"
+    );
+    ranked(db, &query, limit, window)
 }
 
-fn most_played_artists(db: &Connection, limit: u32) -> Result<String, rusqlite::Error> {
-    let query =
-        "select artist,sum(playcount) as p from tracks group by artist order by p desc limit ?1"
-            .to_string();
-    Ok(db
-        .prepare(&query)?
-        .query_map([limit], |row| {
-            Ok(format!(
-                "{}: {}",
-                row.get(0)
-                    .unwrap_or("Unknown Artist".to_string())
-                    .italic()
-                    .red(),
-                row.get(1).unwrap_or(0).to_string().bold().green()
-            ))
-        })?
-        .flatten()
-        .join("\n"))
+fn most_played_albums(
+    db: &Connection,
+    limit: u32,
+    window: &TimeWindow,
+) -> Result<Vec<RankedItem>, rusqlite::Error> {
+    let query = format!(
+        "select artist,album,count(*) as count from tracks inner join history on tracks.id = history.songid
+        where history.event = 'play' and {IN_WINDOW} group by artist, album order by count desc limit ?3"
+    );
+    ranked(db, &query, limit, window)
+}
+
+fn most_played_artists(
+    db: &Connection,
+    limit: u32,
+    window: &TimeWindow,
+) -> Result<Vec<RankedItem>, rusqlite::Error> {
+    let query = format!(
+        "select artist,count(*) as count from tracks inner join history on tracks.id = history.songid
+        where history.event = 'play' and {IN_WINDOW} group by artist order by count desc limit ?3"
+    );
+    ranked(db, &query, limit, window)
 }
 
-fn track_count(db: &Connection, unique: bool) -> Result<i32, rusqlite::Error> {
-    let query = match unique {
-        true => "select count(*) from tracks",
-        false => "select count(*) from history",
+fn most_skipped_tracks(
+    db: &Connection,
+    limit: u32,
+    window: &TimeWindow,
+) -> Result<Vec<RankedItem>, rusqlite::Error> {
+    let query = format!(
+        "select artist,album,title,count(*) as count from tracks inner join history on tracks.id = history.songid
+        where history.event = 'skip' and {IN_WINDOW} group by tracks.id order by count desc limit ?3"
+    );
+    ranked(db, &query, limit, window)
+}
+
+fn count(db: &Connection, what: &str, window: &TimeWindow) -> Result<i64, rusqlite::Error> {
+    let query = format!(
+        "select count({what}) from tracks inner join history on tracks.id = history.songid
+        where history.event = 'play' and {IN_WINDOW}"
+    );
+    db.query_row(&query, [&window.since, &window.until], |row| row.get(0))
+}
+
+pub(crate) fn logged<T>(what: &str, result: Result<T, rusqlite::Error>) -> Option<T> {
+    result
+        .inspect_err(|err| error!("Failed to calculate {what}: {err:?}"))
+        .ok()
+}
+
+pub(crate) fn build_report(db: &Connection, window: &TimeWindow, limit: u32) -> StatsReport {
+    StatsReport {
+        since: window.since.clone(),
+        until: window.until.clone(),
+        total_playtime_seconds: logged("total playtime", total_playtime(db, window)),
+        total_listens: logged("track listens", count(db, "*", window)),
+        unique_tracks: logged(
+            "unique track listens",
+            count(db, "distinct history.songid", window),
+        ),
+        albums: logged(
+            "total album count",
+            count(db, "distinct artist || ' - ' || album", window),
+        ),
+        artists: logged("total artist count", count(db, "distinct artist", window)),
+        top_tracks: logged("most played tracks", most_played_tracks(db, limit, window))
+            .unwrap_or_default(),
+        top_albums: logged("most played albums", most_played_albums(db, limit, window))
+            .unwrap_or_default(),
+        top_artists: logged(
+            "most played artists",
+            most_played_artists(db, limit, window),
+        )
+        .unwrap_or_default(),
+        most_skipped: logged(
+            "most skipped tracks",
+            most_skipped_tracks(db, limit, window),
+        )
+        .unwrap_or_default(),
     }
-    .to_string();
-    Ok(db
-        .prepare(&query)?
-        .query_map([], |row| row.get(0))?
-        .flatten()
-        .last()
-        .unwrap_or(-1))
 }
 
-fn album_count(db: &Connection) -> Result<i32, rusqlite::Error> {
-    let query = "select count(distinct album) from tracks".to_string();
-    Ok(db
-        .prepare(&query)?
-        .query_map([], |row| row.get(0))?
-        .flatten()
-        .last()
-        .unwrap_or(-1))
+pub(crate) fn stats_information(
+    db: &Connection,
+    window: &TimeWindow,
+    format: StatsFormat,
+    limit: u32,
+) -> String {
+    let report = build_report(db, window, limit);
+    match format {
+        StatsFormat::Table => build_stats_table(&report, window),
+        StatsFormat::Json => serde_json::to_string(&report).unwrap(),
+        StatsFormat::Csv => build_stats_csv(&report),
+    }
 }
 
-fn artist_count(db: &Connection) -> Result<i32, rusqlite::Error> {
-    let query = "select count(distinct artist) from tracks".to_string();
-    Ok(db
-        .prepare(&query)?
-        .query_map([], |row| row.get(0))?
-        .flatten()
-        .last()
-        .unwrap_or(-1))
+pub(crate) fn format_duration(seconds: u64) -> String {
+    let sec = seconds % 60;
+    let min = (seconds / 60) % 60;
+    let hr = (seconds / 60) / 60;
+    format!("{hr:0>2}:{min:0>2}:{sec:0>2}")
 }
 
-pub(crate) fn print_stats_table(db: &Connection) {
-    let mut table_builder = Builder::with_capacity(7, 2);
+fn colored_count(count: Option<i64>) -> String {
+    count
+        .map(|c| c.to_string())
+        .unwrap_or("Unknown".to_string())
+        .bold()
+        .green()
+        .to_string()
+}
+
+pub(crate) fn colored_ranking(items: &[RankedItem]) -> String {
+    items
+        .iter()
+        .map(|item| {
+            let name = [
+                Some(item.artist.italic().red()),
+                item.album.as_ref().map(|a| a.italic().blue()),
+                item.title.as_ref().map(|t| t.italic().purple()),
+            ]
+            .into_iter()
+            .flatten()
+            .join(" - ");
+            format!("{name}: {}", item.count.to_string().bold().green())
+        })
+        .join("\n")
+}
+
+fn build_stats_table(report: &StatsReport, window: &TimeWindow) -> String {
+    let mut table_builder = Builder::with_capacity(10, 2);
+    if let Some(period) = window.describe() {
+        table_builder.push_record(["Period".italic().to_string(), period.bold().to_string()]);
+    }
     [
         vec![
             "Total Playtime".italic().to_string(),
-            match total_playtime(db) {
-                Ok(time) => {
-                    let sec = time.as_secs() % 60;
-                    let min = (time.as_secs() / 60) % 60;
-                    let hr = (time.as_secs() / 60) / 60;
-
-                    format!("{hr:0>2}:{min:0>2}:{sec:0>2}")
-                }
-                Err(err) => {
-                    error!("Failed to calculate total playtime: {err:?}");
-                    "Unknown".to_string()
-                }
-            }
-            .bold()
-            .green()
-            .to_string(),
+            report
+                .total_playtime_seconds
+                .map(format_duration)
+                .unwrap_or("Unknown".to_string())
+                .bold()
+                .green()
+                .to_string(),
         ],
         vec![
             "Total Track Listens".italic().to_string(),
-            match track_count(db, false) {
-                Ok(-1) => "Unknown".to_string(),
-                Ok(ct) => ct.to_string(),
-                Err(err) => {
-                    error!("Failed to calculate track listens: {err:?}");
-                    "Unknown".to_string()
-                }
-            }
-            .bold()
-            .green()
-            .to_string(),
+            colored_count(report.total_listens),
         ],
         vec![
             "Unique Track Listens".italic().to_string(),
-            match track_count(db, true) {
-                Ok(-1) => "Unknown".to_string(),
-                Ok(ct) => ct.to_string(),
-                Err(err) => {
-                    error!("Failed to calculate unique track listens: {err:?}");
-                    "Unknown".to_string()
-                }
-            }
-            .bold()
-            .green()
-            .to_string(),
+            colored_count(report.unique_tracks),
         ],
         vec![
-            "Total Albums In Eurydice Collection".italic().to_string(),
-            match album_count(db) {
-                Ok(-1) => "Unknown".to_string(),
-                Ok(ct) => ct.to_string(),
-                Err(err) => {
-                    error!("Failed to calculate total album count: {err:?}");
-                    "Unknown".to_string()
-                }
-            }
-            .bold()
-            .green()
-            .to_string(),
+            "Albums Listened To".italic().to_string(),
+            colored_count(report.albums),
         ],
         vec![
-            "Total Artists In Eurydice Collection".italic().to_string(),
-            match artist_count(db) {
-                Ok(-1) => "Unknown".to_string(),
-                Ok(ct) => ct.to_string(),
-                Err(err) => {
-                    error!("Failed to calculate total artist count: {err:?}");
-                    "Unknown".to_string()
-                }
-            }
-            .bold()
-            .green()
-            .to_string(),
+            "Artists Listened To".italic().to_string(),
+            colored_count(report.artists),
         ],
         vec![
             "Most Played Tracks".italic().to_string(),
-            match most_played_track(db, 5, None) {
-                Ok(tracks) => tracks,
-                Err(err) => {
-                    error!("Failed to calculate most played tracks: {err:?}");
-                    "Unknown".to_string()
-                }
-            },
+            colored_ranking(&report.top_tracks),
         ],
         vec![
             "Most Played Albums\n(By Total Track Listens)"
                 .italic()
                 .to_string(),
-            match most_played_albums(db, 5) {
-                Ok(tracks) => tracks,
-                Err(err) => {
-                    error!("Failed to calculate most played albums: {err:?}");
-                    "Unknown".to_string()
-                }
-            },
+            colored_ranking(&report.top_albums),
         ],
         vec![
             "Most Played Artists".italic().to_string(),
-            match most_played_artists(db, 5) {
-                Ok(tracks) => tracks,
-                Err(err) => {
-                    error!("Failed to calculate most played artists: {err:?}");
-                    "Unknown".to_string()
-                }
-            },
+            colored_ranking(&report.top_artists),
+        ],
+        vec![
+            "Most Skipped Tracks".italic().to_string(),
+            colored_ranking(&report.most_skipped),
         ],
     ]
     .iter()
     .for_each(|r| table_builder.push_record(r));
     let mut table = table_builder.build();
-    let printable = table.with(Style::modern_rounded());
-    println!("{printable}")
+    table.with(Style::modern_rounded()).to_string()
+}
+
+/// Quote a CSV field if it needs it, per RFC 4180.
+pub(crate) fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+/// Flatten the report into one CSV table: a row per summary figure, then a row per ranked item
+/// with its position in the list.
+fn build_stats_csv(report: &StatsReport) -> String {
+    let mut rows = vec!["section,rank,artist,album,title,count".to_string()];
+    for (section, value) in [
+        (
+            "total_playtime_seconds",
+            report.total_playtime_seconds.map(|s| s as i64),
+        ),
+        ("total_listens", report.total_listens),
+        ("unique_tracks", report.unique_tracks),
+        ("albums", report.albums),
+        ("artists", report.artists),
+    ] {
+        rows.push(format!(
+            "{section},,,,,{}",
+            value.map(|v| v.to_string()).unwrap_or_default()
+        ));
+    }
+    for (section, items) in [
+        ("top_tracks", &report.top_tracks),
+        ("top_albums", &report.top_albums),
+        ("top_artists", &report.top_artists),
+        ("most_skipped", &report.most_skipped),
+    ] {
+        for (rank, item) in items.iter().enumerate() {
+            rows.push(
+                [
+                    section.to_string(),
+                    (rank + 1).to_string(),
+                    csv_field(&item.artist),
+                    csv_field(item.album.as_deref().unwrap_or_default()),
+                    csv_field(item.title.as_deref().unwrap_or_default()),
+                    item.count.to_string(),
+                ]
+                .join(","),
+            );
+        }
+    }
+    rows.join("\n")
 }
@@ -0,0 +1,104 @@
+use log::{debug, info};
+use rusqlite::{Connection, OptionalExtension};
+
+use crate::mpd_client::{MPDClient, escape};
+use crate::mpd_response::CommandError;
+
+// NOTE: the same names myMPD uses, so it shows them as its own
+const PLAY_COUNT: &str = "playCount";
+const LAST_PLAYED: &str = "lastPlayed";
+
+/// MPD's `ACK_ERROR_NO_EXIST`, for a song that isn't in its database.
+const NO_SUCH_SONG: u32 = 50;
+
+/// Each file's plays, summed over every row recorded for it, and when it was last played in unix
+/// time. Needs `PLAYED_FILES` after any further conditions.
+const FILE_PLAYS: &str = "SELECT file, sum(playcount), CAST(strftime('%s', max((
+        SELECT max(time) FROM history WHERE history.songid = tracks.id AND history.event = 'play'
+    ))) AS INTEGER)
+    FROM tracks WHERE file IS NOT NULL";
+/// Files that have only ever been skipped have nothing to share.
+const PLAYED_FILES: &str = " GROUP BY file HAVING sum(playcount) > 0";
+
+#[derive(Debug, Default)]
+pub(crate) struct SyncReport {
+    pub(crate) written: usize,
+    /// Recorded files MPD doesn't have any more
+    pub(crate) missing: usize,
+}
+
+/// MPD only offers the `sticker` command when it has a `sticker_file` to keep them in, which it
+/// doesn't out of the box.
+pub(crate) fn available(client: &mut MPDClient) -> bool {
+    match client.send_command("commands\n".to_string()) {
+        Ok(commands) => commands.get_all("command").any(|c| c == "sticker"),
+        Err(err) => {
+            debug!("Could not list MPD commands: {err}");
+            false
+        }
+    }
+}
+
+fn write(
+    client: &mut MPDClient,
+    file: &str,
+    play_count: i64,
+    last_played: Option<i64>,
+) -> Result<(), CommandError> {
+    let set = |name: &str, value: i64| {
+        format!(
+            "sticker set song \"{}\" \"{name}\" \"{value}\"\n",
+            escape(file)
+        )
+    };
+    let command = "command_list_begin\n".to_owned()
+        + &set(PLAY_COUNT, play_count)
+        + &last_played.map(|t| set(LAST_PLAYED, t)).unwrap_or_default()
+        + "command_list_end\n";
+    client.send_command(command).map(|_| ())
+}
+
+/// Bring a file's stickers up to date with what's recorded, e.g. just after recording a play.
+pub(crate) fn update(db: &Connection, client: &mut MPDClient, file: &str) -> Result<(), String> {
+    let Some((play_count, last_played)) = db
+        .query_row(
+            &(FILE_PLAYS.to_string() + " AND file = ?1" + PLAYED_FILES),
+            [file],
+            |row| Ok((row.get(1)?, row.get(2)?)),
+        )
+        .optional()
+        .map_err(|err| format!("{err:?}"))?
+    else {
+        debug!("No plays recorded for {file}, no stickers to write");
+        return Ok(());
+    };
+    debug!("Writing stickers for {file}: {play_count} plays, last at {last_played:?}");
+    write(client, file, play_count, last_played).map_err(|err| err.to_string())
+}
+
+/// Write the stickers of every recorded file, for when they've been lost or were never written.
+pub(crate) fn sync(db: &Connection, client: &mut MPDClient) -> Result<SyncReport, String> {
+    let rows: Vec<(String, i64, Option<i64>)> = db
+        .prepare(&(FILE_PLAYS.to_string() + PLAYED_FILES))
+        .and_then(|mut query| {
+            query
+                .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))?
+                .collect()
+        })
+        .map_err(|err| format!("Could not read play counts: {err:?}"))?;
+
+    let mut report = SyncReport::default();
+    for (file, play_count, last_played) in rows {
+        match write(client, &file, play_count, last_played) {
+            Ok(()) => report.written += 1,
+            Err(CommandError::Ack(err)) if err.code == NO_SUCH_SONG => {
+                debug!("{file} is not in the MPD library, skipping");
+                report.missing += 1;
+            }
+            // NOTE: anything else, like MPD having no sticker database, fails every file the same
+            Err(err) => return Err(format!("Could not write stickers for {file}: {err}")),
+        }
+    }
+    info!("Synced stickers: {report:?}");
+    Ok(report)
+}
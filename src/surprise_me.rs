@@ -1,113 +1,690 @@
+use colored::Colorize;
 use itertools::Itertools;
-use log::{debug, trace};
+use log::{debug, trace, warn};
 use rusqlite::Connection;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt;
+use std::path::Path;
+use std::str::FromStr;
+use tabled::{builder::Builder, settings::Style};
 
-pub(crate) fn create_track_playlist(
-    db: &Connection,
-    target_length: Option<f32>,
-    same_artist: bool,
-) -> Result<Vec<SelectedTrack>, rusqlite::Error> {
-    // Default to one hour
-    let target_length = target_length.unwrap_or(60.0) * 60.0;
+use crate::filters::TagFilters;
+use crate::ignore::IgnoreRules;
+use crate::mpd_client::MPDClient;
+use crate::mpd_response::Response;
+use crate::ratings::{self, Rating};
+use crate::sampling::{Candidate, Sampler};
+use crate::stats::format_duration;
 
-    debug!(
-        "Creating track playlist of {target_length} minutes with same artist set to {same_artist}"
-    );
+/// Common table expression giving every track's plays plus skips, for down-weighting tracks that
+/// keep getting skipped, and how many days ago it was last heard and last played (null if never).
+const HEARD_COUNTS: &str = "with heard as (
+        select tracks.*, playcount + (
+            select count(*) from history where history.songid = tracks.id and history.event = 'skip'
+        ) as heardcount, julianday('now') - julianday(
+            (select max(time) from history where history.songid = tracks.id)
+        ) as days_since_heard, julianday('now') - julianday(
+            (select max(time) from history where history.songid = tracks.id and history.event = 'play')
+        ) as days_since_played from tracks
+    )
+    ";
+
+/// How long ago something was, written as a count and a unit: `12h`, `30d`, `2w`, `6m` or `1y`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+// for the config file
+#[derive(Deserialize, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub(crate) struct Age {
+    count: u32,
+    unit: char,
+}
+
+impl Age {
+    pub(crate) fn days(&self) -> f64 {
+        let days_per_unit = match self.unit {
+            'h' => 1.0 / 24.0,
+            'd' => 1.0,
+            'w' => 7.0,
+            'm' => 30.0,
+            'y' => 365.0,
+            _ => unreachable!("Ages are only built from valid units"),
+        };
+        self.count as f64 * days_per_unit
+    }
+}
+
+impl FromStr for Age {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let Some(unit) = s.chars().last().filter(|u| "hdwmy".contains(*u)) else {
+            return Err(format!("{s:?} needs a unit of h, d, w, m or y (e.g. 30d)"));
+        };
+        let count = s[..s.len() - 1]
+            .parse()
+            .map_err(|err| format!("{s:?} doesn't start with a whole number: {err}"))?;
+        Ok(Age { count, unit })
+    }
+}
+
+impl TryFrom<String> for Age {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl fmt::Display for Age {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}{}", self.count, self.unit)
+    }
+}
+
+impl From<Age> for String {
+    fn from(age: Age) -> Self {
+        age.to_string()
+    }
+}
+
+/// How a file has been listened to so far, from `tracks` and `history`.
+#[derive(Debug, Clone, Default)]
+struct Listens {
+    plays: i64,
+    heard: f64,
+    days_since_heard: Option<f64>,
+    days_since_played: Option<f64>,
+}
+
+impl Listens {
+    fn merge(&mut self, other: &Listens) {
+        self.plays += other.plays;
+        self.heard += other.heard;
+        self.days_since_heard = min_days(self.days_since_heard, other.days_since_heard);
+        self.days_since_played = min_days(self.days_since_played, other.days_since_played);
+    }
+
+    fn played_within(&self, age: Option<Age>) -> bool {
+        age.zip(self.days_since_played)
+            .is_some_and(|(age, days)| days < age.days())
+    }
+}
+
+fn min_days(a: Option<f64>, b: Option<f64>) -> Option<f64> {
+    match (a, b) {
+        (Some(a), Some(b)) => Some(a.min(b)),
+        _ => a.or(b),
+    }
+}
+
+/// The tags surprise-me picks tracks and albums by.
+#[derive(Debug, Clone)]
+struct TrackTags {
+    album: Option<String>,
+    album_artist: Option<String>,
+    disc: Option<u32>,
+    track: Option<u32>,
+    duration: Option<f32>,
+}
+
+impl TrackTags {
+    fn new(song: &Response) -> TrackTags {
+        // NOTE: Disc and Track are often written as "3/12"
+        let number = |tag: &str| {
+            song.get(tag)
+                .and_then(|n| n.split('/').next())
+                .and_then(|n| n.trim().parse().ok())
+        };
+        TrackTags {
+            album: song.get("Album").map(|a| a.to_string()),
+            album_artist: song.get("AlbumArtist").map(|a| a.to_string()),
+            disc: number("Disc"),
+            track: number("Track"),
+            duration: song.get("duration").and_then(|d| d.parse().ok()),
+        }
+    }
+}
+
+/// A track in the MPD library along with how it's been listened to, if at all.
+#[derive(Debug, Clone)]
+pub(crate) struct LibraryTrack {
+    track: SelectedTrack,
+    title: String,
+    album: Option<AlbumKey>,
+    disc: Option<u32>,
+    number: Option<u32>,
+    listens: Listens,
+    rating: Rating,
+}
+
+/// Which album a track is on. Plenty of albums share a name ("Greatest Hits", self-titled ones),
+/// so it's the album of that name by the album artist or, without an album artist tag, the one in
+/// the same directory.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+struct AlbumKey {
+    album: String,
+    album_artist: Option<String>,
+    directory: Option<String>,
+}
+
+impl AlbumKey {
+    fn new(file: &str, tags: &TrackTags) -> Option<AlbumKey> {
+        Some(AlbumKey {
+            album: tags.album.clone()?,
+            album_artist: tags.album_artist.clone(),
+            directory: match tags.album_artist {
+                Some(_) => None,
+                None => Path::new(file)
+                    .parent()
+                    .map(|dir| dir.to_str().unwrap().to_string()),
+            },
+        })
+    }
+}
 
-    // TODO: I'm sure there's a way to do this greedy calculation in sqlite itself
-    // FIXME: I've hardcoded 300 as the limit here intentionally since it gives me a decent
-    // boundary for having enough songs (works out to ~12hrs of tracks even with wide variance in
-    // length). I should probably do something less dumb (see above for greedy calculation in
-    // sqlite)
-    // NOTE: I'm just using the average play count as a marker here (choosing things with below
-    // average plays). It may be statistically more satisfying to use the median, but that's not
-    // built in to sqlite and I can't imagine in a real scenario playing one song so much that it
-    // skews the average.
-    let query_str =
-        "select * from
-            (select artist,path,lengthseconds from tracks where playcount <= (select avg(playcount) from tracks) limit 300)
-        order by random()"
-            .to_string();
-
-    let mut query = db.prepare(query_str.as_str())?;
-    let mut random_tracks: Vec<SelectedTrack> = query
+/// The tracks surprise-me can pick from, and how big the library was before any of them were
+/// filtered out, for `--explain`.
+#[derive(Debug, Clone)]
+pub(crate) struct Library {
+    tracks: Vec<LibraryTrack>,
+    total_tracks: usize,
+    total_albums: usize,
+}
+
+/// Every track in the MPD library that isn't ignored, joined to the play history on the file URI.
+/// Tracks that have never been played are exactly the ones most worth rediscovering, so they're
+/// candidates too, just with no plays. Recorded tracks that are no longer in the library are left
+/// out since they can't be queued anyway.
+pub(crate) fn library_tracks(
+    db: &Connection,
+    client: &mut MPDClient,
+    music_dir: &Path,
+    ignore: &IgnoreRules,
+    filters: &TagFilters,
+) -> Result<Library, rusqlite::Error> {
+    // NOTE: rows from before tracks had a file recorded only have the (possibly absolute) path,
+    // so key on both. `eurydice db reconcile` fills in the file for those.
+    let mut listens = HashMap::<String, Listens>::new();
+    let query_str = HEARD_COUNTS.to_string()
+        + "select file, path, playcount, heardcount, days_since_heard, days_since_played from heard";
+    for row in db
+        .prepare(query_str.as_str())?
         .query_map([], |row| {
-            Ok(SelectedTrack {
-                artist: row.get(0)?,
-                path: row.get(1)?,
-                length: row.get(2)?,
-            })
+            Ok((
+                row.get::<_, Option<String>>(0)?,
+                row.get::<_, Option<String>>(1)?,
+                Listens {
+                    plays: row.get(2)?,
+                    heard: row.get(3)?,
+                    days_since_heard: row.get(4)?,
+                    days_since_played: row.get(5)?,
+                },
+            ))
         })?
         .flatten()
+    {
+        let (file, path, heard) = row;
+        let key = file.or(path).unwrap_or_default();
+        listens.entry(key).or_default().merge(&heard);
+    }
+
+    let ratings = ratings::all(db)?;
+    // NOTE: every song by its file, so the same title on two albums (or two untitled tracks) are
+    // still two tracks
+    let library = client.library().expect("MPD could not list the library");
+    let total_albums = library
+        .iter()
+        .filter_map(|song| AlbumKey::new(song.get("file")?, &TrackTags::new(song)))
+        .unique()
+        .count();
+    let matching = filters.matching_files(client);
+    let tracks: Vec<LibraryTrack> = library
+        .iter()
+        .filter(|song| !ignore.ignores_song(song))
+        .filter_map(|song| {
+            let file = song.get("file")?;
+            if matching.as_ref().is_some_and(|m| !m.contains(file))
+                || ratings.get(file).is_some_and(|r| r.banned)
+            {
+                return None;
+            }
+            let tags = TrackTags::new(song);
+            let Some(length) = tags.duration else {
+                warn!("No duration reported for {file}, not picking it");
+                return None;
+            };
+            if !filters.allows_length(length) {
+                return None;
+            }
+            let path = music_dir.join(file).to_str().unwrap().to_string();
+            let heard = listens
+                .get(file)
+                .or_else(|| listens.get(&path))
+                .cloned()
+                .unwrap_or_default();
+            Some(LibraryTrack {
+                track: SelectedTrack {
+                    artist: song
+                        .get("AlbumArtist")
+                        .or(song.get("Artist"))
+                        .unwrap_or("Unknown")
+                        .to_string(),
+                    path,
+                    length,
+                },
+                title: song.get("Title").unwrap_or("Unknown").to_string(),
+                album: AlbumKey::new(file, &tags),
+                disc: tags.disc,
+                number: tags.track,
+                listens: heard,
+                rating: ratings.get(file).copied().unwrap_or_default(),
+            })
+        })
+        // NOTE: the library comes back in hash order, so sort it for a seed to always give the
+        // same picks
+        .sorted_by(|a, b| a.track.path.cmp(&b.track.path))
         .collect();
-    trace!("All random tracks: {random_tracks:?}");
+    debug!(
+        "{} library tracks, {} never played",
+        tracks.len(),
+        tracks.iter().filter(|t| t.listens.heard == 0.0).count()
+    );
+    Ok(Library {
+        tracks,
+        total_tracks: library.len(),
+        total_albums,
+    })
+}
 
-    // just take the first artist for simplicity
-    if same_artist {
-        let artist = &random_tracks.first().unwrap().artist;
-        random_tracks = random_tracks
+/// What surprise-me picked, with enough about how it got there for `--dry-run` and `--explain`.
+pub(crate) struct Selection {
+    pub(crate) tracks: Vec<SelectedTrack>,
+    /// The tracks or albums picked, with what went into picking each one
+    picks: Vec<Pick>,
+    /// Each step from the library to the picks, in order
+    steps: Vec<String>,
+}
+
+/// A picked track or album.
+struct Pick {
+    name: String,
+    listens: Listens,
+    rating: Rating,
+    length: f32,
+    weight: f64,
+}
+
+impl Selection {
+    pub(crate) fn picks_table(&self) -> String {
+        let mut table_builder = Builder::default();
+        table_builder.push_record(
+            ["Pick", "Plays", "Last Played", "Rating", "Length", "Weight"]
+                .map(|h| h.bold().to_string()),
+        );
+        for pick in &self.picks {
+            table_builder.push_record([
+                pick.name.clone(),
+                pick.listens.plays.to_string(),
+                match pick.listens.days_since_played {
+                    Some(days) if days < 1.0 => "today".to_string(),
+                    Some(days) => format!("{} days ago", days.floor()),
+                    None => "never".to_string(),
+                },
+                pick.rating.describe(),
+                format_duration(pick.length.round() as u64),
+                format!("{:.4}", pick.weight),
+            ]);
+        }
+        let mut table = table_builder.build();
+        table.with(Style::modern_rounded()).to_string()
+    }
+
+    pub(crate) fn explanation(&self, filters: &TagFilters) -> String {
+        let filters = filters.describe();
+        let filters = if filters.is_empty() {
+            "none".to_string()
+        } else {
+            filters.join("; ")
+        };
+        [format!("Tag filters: {filters}")]
             .iter()
-            .filter(|t| t.artist == *artist)
-            .map(|t| t.to_owned())
-            .collect();
-        trace!("Tracks filtered by artist: {random_tracks:?}");
+            .chain(&self.steps)
+            .join("\n")
     }
+}
 
-    // greedily take until we reach the target length
-    let mut sum = 0.0;
-    let tracks = random_tracks
-        .iter()
-        .take_while(|t| {
-            sum += t.length;
-            sum <= target_length
+/// How long a playlist should be, in minutes, and how far either side of that is close enough.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct PlaylistLength {
+    pub(crate) target: f32,
+    pub(crate) tolerance: f32,
+    /// Fail rather than settle for the closest fit when nothing lands within the tolerance
+    pub(crate) strict: bool,
+}
+
+pub(crate) fn create_track_playlist(
+    library: Library,
+    length: PlaylistLength,
+    same_artist: bool,
+    candidate_limit: u32,
+    not_played_within: Option<Age>,
+    min_rating: Option<u8>,
+    sampler: &mut Sampler,
+) -> Result<Selection, String> {
+    debug!(
+        "Creating track playlist of {length:?} with same artist set to {same_artist}, not played within {not_played_within:?}, rated at least {min_rating:?}"
+    );
+
+    // FIXME: the candidate limit (300 by default) is there intentionally since it gives me a
+    // decent boundary for having enough songs (works out to ~12hrs of tracks even with wide
+    // variance in length). I should probably do something less dumb.
+    // NOTE: skips count the same as plays here, so tracks that keep getting skipped drift out of
+    // the pool the same way often played ones do.
+    let mut steps = vec![
+        format!("Library: {} tracks", library.total_tracks),
+        format!(
+            "After tag filters, ignores and bans: {} tracks",
+            library.tracks.len()
+        ),
+    ];
+    let library: Vec<LibraryTrack> = library
+        .tracks
+        .into_iter()
+        .filter(|t| min_rating.is_none_or(|min| t.rating.stars.is_some_and(|s| s >= min)))
+        .collect();
+    if let Some(min) = min_rating {
+        steps.push(format!(
+            "Rated at least {min} stars: {} tracks",
+            library.len()
+        ));
+    }
+    let candidates: Vec<_> = library
+        .into_iter()
+        .filter(|t| !t.listens.played_within(not_played_within))
+        .map(|t| Candidate {
+            heard: t.listens.heard,
+            days_since_heard: t.listens.days_since_heard,
+            preference: t.rating.preference(),
+            item: t,
         })
-        .map(|t| t.to_owned())
         .collect();
+    if let Some(age) = not_played_within {
+        steps.push(format!(
+            "Not played within {age}: {} tracks",
+            candidates.len()
+        ));
+    }
+    let mut random_tracks: Vec<LibraryTrack> = sampler
+        .shuffle(candidates)
+        .into_iter()
+        .take(candidate_limit as usize)
+        .collect();
+    steps.push(format!(
+        "Candidate pool: {} tracks (limit {candidate_limit})",
+        random_tracks.len()
+    ));
+    trace!("All random tracks: {random_tracks:?}");
+
+    // just take the first artist for simplicity
+    if same_artist && let Some(first) = random_tracks.first() {
+        let artist = first.track.artist.clone();
+        random_tracks.retain(|t| t.track.artist == artist);
+        steps.push(format!(
+            "Same artist as the top pick ({artist}): {} tracks",
+            random_tracks.len()
+        ));
+        trace!("Tracks filtered by artist: {random_tracks:?}");
+    }
+
+    let tracks = fit_length(random_tracks, length)?;
+    let total: f32 = tracks.iter().map(|t| t.track.length).sum();
+    steps.push(format!(
+        "Length: {} of a {} target, give or take {}",
+        format_minutes(total.round() as usize),
+        format_minutes((length.target * 60.0).round() as usize),
+        format_minutes((length.tolerance * 60.0).round() as usize)
+    ));
     trace!("Final track list: {tracks:?}");
+    Ok(Selection {
+        picks: tracks
+            .iter()
+            .map(|t| Pick {
+                name: format!("{} - {}", t.track.artist, t.title),
+                listens: t.listens.clone(),
+                rating: t.rating,
+                length: t.track.length,
+                weight: sampler.weight(
+                    t.listens.heard,
+                    t.listens.days_since_heard,
+                    t.rating.preference(),
+                ),
+            })
+            .collect(),
+        tracks: tracks.into_iter().map(|t| t.track).collect(),
+        steps,
+    })
+}
+
+/// Pick tracks adding up to the target length, give or take the tolerance, from candidates in
+/// order of preference.
+///
+/// This is a 0/1 knapsack over whole seconds: `reached[s]` holds the track that first made a
+/// total of `s` reachable, built on totals from tracks before it. Candidates are added one at a
+/// time and the search stops as soon as a total lands in the window, so the playlist comes from
+/// the shortest possible prefix of the candidates, i.e. the most preferred ones. Anything after
+/// that first fit is never considered.
+fn fit_length(
+    candidates: Vec<LibraryTrack>,
+    length: PlaylistLength,
+) -> Result<Vec<LibraryTrack>, String> {
+    let target = (length.target * 60.0).round().max(0.0) as usize;
+    let tolerance = (length.tolerance * 60.0).round().max(0.0) as usize;
+    let (low, high) = (target.saturating_sub(tolerance), target + tolerance);
+
+    // NOTE: track 0 can't be told apart from "reached without any tracks", hence the sentinel
+    const START: usize = usize::MAX;
+    let mut reached: Vec<Option<usize>> = vec![None; high + 1];
+    reached[0] = Some(START);
+    let closest_in = |reached: &[Option<usize>], range: std::ops::RangeInclusive<usize>| {
+        range
+            .filter(|s| reached[*s].is_some())
+            .min_by_key(|s| s.abs_diff(target))
+    };
+
+    let seconds = |t: &LibraryTrack| t.track.length.round().max(0.0) as usize;
+    let mut fit = None;
+    for (i, track) in candidates.iter().enumerate() {
+        let len = seconds(track);
+        if len == 0 || len > high {
+            continue;
+        }
+        // downwards, so each track is only used once
+        for s in (len..=high).rev() {
+            if reached[s].is_none() && reached[s - len].is_some() {
+                reached[s] = Some(i);
+            }
+        }
+        if let Some(total) = closest_in(&reached, low..=high) {
+            fit = Some(total);
+            break;
+        }
+    }
+
+    let total = match fit {
+        Some(total) => total,
+        None if length.strict => {
+            return Err(format!(
+                "No combination of {} candidate tracks fits {} minutes within {} minutes",
+                candidates.len(),
+                length.target,
+                length.tolerance
+            ));
+        }
+        None => {
+            let total = closest_in(&reached, 0..=high).unwrap_or(0);
+            warn!(
+                "No combination of tracks fits {} minutes within {} minutes, settling for {}",
+                length.target,
+                length.tolerance,
+                format_minutes(total)
+            );
+            total
+        }
+    };
+    debug!("Filled {} of {target}s", format_minutes(total));
+
+    let mut tracks = Vec::new();
+    let mut s = total;
+    while let Some(i) = reached[s].filter(|i| *i != START) {
+        tracks.push(candidates[i].clone());
+        s -= seconds(&candidates[i]);
+    }
+    // back in order of preference
+    tracks.reverse();
     Ok(tracks)
 }
 
+fn format_minutes(seconds: usize) -> String {
+    format!("{}:{:0>2}", seconds / 60, seconds % 60)
+}
+
+/// How an album's tracks have been heard and rated, taken together.
+struct AlbumStats {
+    /// Per track, on average
+    heard: f64,
+    listens: Listens,
+    rating: Rating,
+    preference: f64,
+}
+
 pub(crate) fn create_album_playlist(
-    db: &Connection,
-    count: Option<u16>,
-) -> Result<Vec<SelectedTrack>, rusqlite::Error> {
-    // Default to one album
-    let count = count.unwrap_or(1);
-    debug!("Creating album playlist of {count}");
-
-    // get a random set of low played albums
-    let query_str = "select distinct album from tracks 
-            where playcount <= (select avg(playcount) from tracks) and album != 'Unknown Album' 
-            order by random() limit ?1;"
-        .to_string();
-
-    let mut query = db.prepare(query_str.as_str())?;
-    let album_names: Vec<String> = query
-        .query_map([&count], |row| row.get(0))?
-        .flatten()
+    library: Library,
+    count: u16,
+    not_played_within: Option<Age>,
+    min_rating: Option<u8>,
+    sampler: &mut Sampler,
+) -> Selection {
+    debug!(
+        "Creating album playlist of {count}, not played within {not_played_within:?}, rated at least {min_rating:?}"
+    );
+
+    let mut albums = library
+        .tracks
+        .into_iter()
+        .filter_map(|t| Some((t.album.clone()?, t)))
+        .into_group_map();
+    // NOTE: tracks without numbers go last, in path order
+    albums.values_mut().for_each(|tracks| {
+        tracks.sort_by(|a, b| {
+            (a.disc.is_none(), a.disc, a.number.is_none(), a.number)
+                .cmp(&(b.disc.is_none(), b.disc, b.number.is_none(), b.number))
+                .then_with(|| a.track.path.cmp(&b.track.path))
+        })
+    });
+
+    // get a random set of albums, weighted by how much an average track on them has been heard
+    // and liked, and when any of it was last heard. An album counts as played as soon as any
+    // track on it is, and is rated by the average of the tracks on it that are.
+    let album_stats: HashMap<&AlbumKey, AlbumStats> = albums
+        .iter()
+        .map(|(album, tracks)| {
+            let mut listens = Listens::default();
+            tracks.iter().for_each(|t| listens.merge(&t.listens));
+            let stars = tracks.iter().filter_map(|t| t.rating.stars).collect_vec();
+            let stats = AlbumStats {
+                heard: listens.heard / tracks.len() as f64,
+                listens,
+                rating: Rating {
+                    stars: (!stars.is_empty()).then(|| {
+                        (stars.iter().map(|s| *s as f32).sum::<f32>() / stars.len() as f32).round()
+                            as u8
+                    }),
+                    loved: tracks.iter().any(|t| t.rating.loved),
+                    banned: false,
+                },
+                preference: tracks.iter().map(|t| t.rating.preference()).sum::<f64>()
+                    / tracks.len() as f64,
+            };
+            (album, stats)
+        })
         .collect();
-    trace!("Selected albums: {album_names:?}");
+    let mut steps = vec![
+        format!("Library: {} albums", library.total_albums),
+        format!(
+            "After tag filters, ignores and bans: {} albums",
+            albums.len()
+        ),
+    ];
+    let rated = album_stats
+        .iter()
+        .filter(|(_, stats)| {
+            min_rating.is_none_or(|min| stats.rating.stars.is_some_and(|s| s >= min))
+        })
+        .collect_vec();
+    if let Some(min) = min_rating {
+        steps.push(format!(
+            "Rated at least {min} stars: {} albums",
+            rated.len()
+        ));
+    }
+    let candidates: Vec<_> = rated
+        .into_iter()
+        .filter(|(_, stats)| !stats.listens.played_within(not_played_within))
+        .map(|(album, stats)| Candidate {
+            item: *album,
+            heard: stats.heard,
+            days_since_heard: stats.listens.days_since_heard,
+            preference: stats.preference,
+        })
+        .sorted_by_key(|c| c.item)
+        .collect();
+    if let Some(age) = not_played_within {
+        steps.push(format!(
+            "Not played within {age}: {} albums",
+            candidates.len()
+        ));
+    }
+    let album_keys: Vec<&AlbumKey> = sampler
+        .shuffle(candidates)
+        .into_iter()
+        .take(count as usize)
+        .collect();
+    trace!("Selected albums: {album_keys:?}");
 
-    // and now query for the actual tracks
-    let query_str = "select artist,path,lengthseconds from tracks where album in (".to_string()
-        + &album_names
-            .iter()
-            .map(|a| "'".to_string() + a + "'")
-            .join(",")
-        + ")";
-    let tracks = db
-        .prepare(query_str.as_str())?
-        .query_map([], |row| {
-            Ok(SelectedTrack {
-                artist: row.get(0)?,
-                path: row.get(1)?,
-                length: row.get(2)?,
-            })
-        })?
-        .flatten()
+    let tracks: Vec<SelectedTrack> = album_keys
+        .iter()
+        .flat_map(|album| albums[*album].iter().map(|t| t.track.clone()))
         .collect();
+    let total: f32 = tracks.iter().map(|t| t.length).sum();
+    steps.push(format!(
+        "Length: {} over {} of {count} albums asked for",
+        format_minutes(total.round() as usize),
+        album_keys.len()
+    ));
     trace!("Final track list: {tracks:?}");
-    Ok(tracks)
+    Selection {
+        picks: album_keys
+            .iter()
+            .map(|album| {
+                let stats = &album_stats[album];
+                Pick {
+                    name: format!("{} - {}", albums[*album][0].track.artist, album.album),
+                    listens: stats.listens.clone(),
+                    rating: stats.rating,
+                    length: albums[*album].iter().map(|t| t.track.length).sum(),
+                    weight: sampler.weight(
+                        stats.heard,
+                        stats.listens.days_since_heard,
+                        stats.preference,
+                    ),
+                }
+            })
+            .collect(),
+        tracks,
+        steps,
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -116,3 +693,102 @@ pub(crate) struct SelectedTrack {
     pub(crate) path: String,
     length: f32,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sampling::Weighting;
+
+    fn track(name: &str, seconds: f32) -> LibraryTrack {
+        LibraryTrack {
+            track: SelectedTrack {
+                artist: "Artist".to_string(),
+                path: name.to_string(),
+                length: seconds,
+            },
+            title: name.to_string(),
+            album: None,
+            disc: None,
+            number: None,
+            listens: Listens::default(),
+            rating: Rating::default(),
+        }
+    }
+
+    fn length(target: f32, tolerance: f32, strict: bool) -> PlaylistLength {
+        PlaylistLength {
+            target,
+            tolerance,
+            strict,
+        }
+    }
+
+    fn names(tracks: &[LibraryTrack]) -> Vec<&str> {
+        tracks.iter().map(|t| t.track.path.as_str()).collect()
+    }
+
+    #[test]
+    fn fit_length_exact() {
+        let candidates = vec![track("a", 120.0), track("b", 180.0), track("c", 60.0)];
+        let fit = fit_length(candidates, length(5.0, 0.0, true)).unwrap();
+        assert_eq!(names(&fit), ["a", "b"]);
+    }
+
+    #[test]
+    fn fit_length_within_tolerance() {
+        let candidates = vec![track("a", 250.0), track("b", 130.0), track("c", 110.0)];
+        let fit = fit_length(candidates, length(6.0, 1.0, true)).unwrap();
+        assert_eq!(names(&fit), ["a", "b"]);
+    }
+
+    #[test]
+    fn fit_length_settles_for_closest() {
+        let candidates = vec![track("a", 100.0), track("b", 100.0), track("c", 700.0)];
+        let fit = fit_length(candidates, length(10.0, 1.0, false)).unwrap();
+        assert_eq!(names(&fit), ["a", "b"]);
+    }
+
+    #[test]
+    fn fit_length_strict_fails() {
+        let candidates = vec![track("a", 100.0), track("b", 100.0)];
+        assert!(fit_length(candidates, length(10.0, 1.0, true)).is_err());
+    }
+
+    #[test]
+    fn fit_length_skips_tracks_longer_than_window() {
+        let candidates = vec![track("long", 1000.0), track("a", 300.0)];
+        let fit = fit_length(candidates, length(5.0, 0.0, true)).unwrap();
+        assert_eq!(names(&fit), ["a"]);
+    }
+
+    #[test]
+    fn same_seed_same_playlist() {
+        let tracks: Vec<_> = (0..50)
+            .map(|i| track(&format!("t{i}"), 60.0 + i as f32))
+            .collect();
+        let library = Library {
+            total_tracks: tracks.len(),
+            total_albums: 0,
+            tracks,
+        };
+        let playlist = |seed| {
+            let mut sampler = Sampler::new(Weighting::Random, Some(seed));
+            create_track_playlist(
+                library.clone(),
+                length(15.0, 1.0, false),
+                false,
+                300,
+                None,
+                None,
+                &mut sampler,
+            )
+            .unwrap()
+            .tracks
+            .into_iter()
+            .map(|t| t.path)
+            .collect::<Vec<_>>()
+        };
+        assert_eq!(playlist(7), playlist(7));
+        assert_ne!(playlist(7), playlist(8));
+    }
+}
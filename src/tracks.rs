@@ -0,0 +1,300 @@
+use itertools::Itertools;
+use log::{debug, info, warn};
+use rusqlite::{Connection, OptionalExtension, Transaction, params};
+use std::collections::HashMap;
+use std::path::Path;
+
+use crate::mpd_client::MPDClient;
+use crate::mpd_response::Response;
+
+/// What eurydice stores about a track, pulled out of an MPD song record.
+#[derive(Debug, Clone)]
+pub(crate) struct TrackInfo {
+    /// The song URI relative to the MPD music directory
+    pub(crate) file: String,
+    /// `MUSICBRAINZ_TRACKID`, which despite the name is the MusicBrainz recording id
+    pub(crate) mbid: Option<String>,
+    pub(crate) title: String,
+    pub(crate) artist: String,
+    pub(crate) album: String,
+    pub(crate) duration: f64,
+}
+
+impl TrackInfo {
+    pub(crate) fn from_song(song: &Response) -> Option<TrackInfo> {
+        let file = song.get("file")?;
+        let Some(duration) = song.get("duration").and_then(|d| d.parse().ok()) else {
+            warn!("No duration reported for {file}");
+            return None;
+        };
+        Some(TrackInfo {
+            file: file.to_string(),
+            mbid: song.get("MUSICBRAINZ_TRACKID").map(|m| m.to_string()),
+            title: song.get("Title").unwrap_or(file).to_string(),
+            artist: song.get("Artist").unwrap_or("Unknown Artist").to_string(),
+            album: song.get("Album").unwrap_or("Unknown Album").to_string(),
+            duration,
+        })
+    }
+}
+
+/// Find the row for a track by its file. Failing that, a row with the same MusicBrainz id is
+/// adopted if its file has gone from the library (or was never recorded), e.g. after the file was
+/// moved or retagged. A row for the same recording on another album that's still in the library
+/// is a different track. Rows from before tracks had a file recorded are matched on their tags as
+/// a last resort.
+pub(crate) fn find_track(
+    db: &Connection,
+    track: &TrackInfo,
+    in_library: &mut dyn FnMut(&str) -> bool,
+) -> Result<Option<i64>, rusqlite::Error> {
+    if let Some(id) = db
+        .query_row(
+            "SELECT id FROM tracks WHERE file = ?1 ORDER BY id LIMIT 1",
+            [&track.file],
+            |row| row.get(0),
+        )
+        .optional()?
+    {
+        return Ok(Some(id));
+    }
+
+    if let Some(mbid) = &track.mbid {
+        let same_recording: Vec<(i64, Option<String>)> = db
+            .prepare("SELECT id, file FROM tracks WHERE mbid = ?1 ORDER BY id")?
+            .query_map([mbid], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<Result<_, _>>()?;
+        if let Some((id, _)) = same_recording
+            .into_iter()
+            .find(|(_, file)| file.as_deref().is_none_or(|f| !in_library(f)))
+        {
+            return Ok(Some(id));
+        }
+    }
+
+    db.query_row(
+        "SELECT id FROM tracks WHERE file IS NULL AND title = ?1 AND artist = ?2 AND album = ?3
+        ORDER BY id LIMIT 1",
+        [&track.title, &track.artist, &track.album],
+        |row| row.get(0),
+    )
+    .optional()
+}
+
+/// Find or create the row for a track, refreshing its tags and adding `plays` to its play count.
+pub(crate) fn upsert_track(
+    db: &Connection,
+    track: &TrackInfo,
+    music_dir: &Path,
+    plays: i64,
+    in_library: &mut dyn FnMut(&str) -> bool,
+) -> Result<i64, rusqlite::Error> {
+    let full_path = music_dir.join(&track.file).to_str().unwrap().to_string();
+    match find_track(db, track, in_library)? {
+        Some(id) => {
+            debug!("Updating track {id} for {}", track.file);
+            db.execute(
+                "UPDATE tracks SET title = ?2, artist = ?3, album = ?4, lengthseconds = ?5,
+                    path = ?6, file = ?7, mbid = coalesce(?8, mbid), playcount = playcount + ?9
+                WHERE id = ?1",
+                params![
+                    id,
+                    track.title,
+                    track.artist,
+                    track.album,
+                    track.duration,
+                    full_path,
+                    track.file,
+                    track.mbid,
+                    plays
+                ],
+            )?;
+            Ok(id)
+        }
+        None => {
+            debug!("Adding new track for {}", track.file);
+            db.query_row(
+                "INSERT INTO tracks(title,artist,album,lengthseconds,playcount,path,file,mbid)
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)
+                RETURNING id",
+                params![
+                    track.title,
+                    track.artist,
+                    track.album,
+                    track.duration,
+                    plays,
+                    full_path,
+                    track.file,
+                    track.mbid
+                ],
+                |row| row.get(0),
+            )
+        }
+    }
+}
+
+struct RecordedTrack {
+    id: i64,
+    file: Option<String>,
+    path: Option<String>,
+    title: String,
+    artist: String,
+    album: String,
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct ReconcileReport {
+    pub(crate) identified: usize,
+    pub(crate) unidentified: usize,
+    pub(crate) merged: usize,
+}
+
+/// Fill in the file and MusicBrainz id of every track from the MPD library, then merge rows that
+/// turn out to be the same track, moving their history over to the oldest row still in the library.
+pub(crate) fn reconcile(
+    db: &mut Connection,
+    client: &mut MPDClient,
+    music_dir: &Path,
+) -> Result<ReconcileReport, rusqlite::Error> {
+    let library: Vec<TrackInfo> = client
+        .library()
+        .expect("MPD could not list the library")
+        .iter()
+        .filter_map(TrackInfo::from_song)
+        .collect();
+    let by_file: HashMap<&str, &TrackInfo> = library.iter().map(|t| (t.file.as_str(), t)).collect();
+    let by_tags: HashMap<(&str, &str, &str), Vec<&TrackInfo>> = library
+        .iter()
+        .into_group_map_by(|t| (t.title.as_str(), t.artist.as_str(), t.album.as_str()));
+    debug!("Reconciling against {} library tracks", library.len());
+
+    let tx = db.transaction()?;
+    let mut report = ReconcileReport::default();
+
+    let rows: Vec<RecordedTrack> = tx
+        .prepare("SELECT id, file, path, title, artist, album FROM tracks")?
+        .query_map([], |row| {
+            Ok(RecordedTrack {
+                id: row.get(0)?,
+                file: row.get(1)?,
+                path: row.get(2)?,
+                title: row.get::<_, Option<String>>(3)?.unwrap_or_default(),
+                artist: row.get::<_, Option<String>>(4)?.unwrap_or_default(),
+                album: row.get::<_, Option<String>>(5)?.unwrap_or_default(),
+            })
+        })?
+        .flatten()
+        .collect();
+
+    for RecordedTrack {
+        id,
+        file,
+        path,
+        title,
+        artist,
+        album,
+    } in rows
+    {
+        // NOTE: prefer what we already know, then the recorded path (absolute if it was recorded
+        // over a local socket), then a tag match as long as it's unambiguous
+        let from_path = path.as_deref().map(|p| {
+            Path::new(p)
+                .strip_prefix(music_dir)
+                .ok()
+                .filter(|_| !music_dir.as_os_str().is_empty())
+                .and_then(|p| p.to_str())
+                .unwrap_or(p)
+                .to_string()
+        });
+        let found = file
+            .as_deref()
+            .and_then(|f| by_file.get(f))
+            .or_else(|| from_path.as_deref().and_then(|f| by_file.get(f)))
+            .copied()
+            .or_else(
+                || match by_tags.get(&(title.as_str(), artist.as_str(), album.as_str())) {
+                    Some(matches) if matches.len() == 1 => Some(matches[0]),
+                    _ => None,
+                },
+            );
+
+        match found {
+            Some(track) => {
+                tx.execute(
+                    "UPDATE tracks SET file = ?2, mbid = coalesce(?3, mbid) WHERE id = ?1",
+                    params![id, track.file, track.mbid],
+                )?;
+                report.identified += 1;
+            }
+            None => {
+                warn!("Could not find {artist} - {album} - {title} in the MPD library");
+                report.unidentified += 1;
+            }
+        }
+    }
+
+    // NOTE: the same recording on an album and a compilation shares a MusicBrainz id but is two
+    // tracks, so only rows whose file is gone from the library are merged on it
+    let same_recording: Vec<(String, i64, Option<String>)> = tx
+        .prepare(
+            "SELECT mbid, id, file FROM tracks WHERE mbid IN (
+                SELECT mbid FROM tracks WHERE mbid IS NOT NULL GROUP BY mbid HAVING count(*) > 1
+            ) ORDER BY mbid, id",
+        )?
+        .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))?
+        .flatten()
+        .collect();
+    for (_, rows) in &same_recording
+        .into_iter()
+        .chunk_by(|(mbid, _, _)| mbid.clone())
+    {
+        let (live, gone): (Vec<_>, Vec<_>) =
+            rows.partition(|(_, _, file)| file.as_deref().is_some_and(|f| by_file.contains_key(f)));
+        let mut gone = gone.into_iter().map(|(_, id, _)| id);
+        let Some(keep) = live.first().map(|(_, id, _)| *id).or_else(|| gone.next()) else {
+            continue;
+        };
+        for duplicate in gone {
+            merge(&tx, keep, duplicate)?;
+            report.merged += 1;
+        }
+    }
+
+    let same_file: Vec<(i64, String)> = tx
+        .prepare(
+            "SELECT min(id), group_concat(id) FROM tracks WHERE file IS NOT NULL
+            GROUP BY file HAVING count(*) > 1",
+        )?
+        .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+        .flatten()
+        .collect();
+    for (keep, ids) in same_file {
+        for duplicate in ids.split(',').filter_map(|id| id.parse::<i64>().ok()) {
+            if duplicate != keep {
+                merge(&tx, keep, duplicate)?;
+                report.merged += 1;
+            }
+        }
+    }
+
+    tx.commit()?;
+    info!("Reconciled tracks: {report:?}");
+    Ok(report)
+}
+
+/// Fold a duplicate row into the one being kept, history and all.
+fn merge(tx: &Transaction, keep: i64, duplicate: i64) -> Result<(), rusqlite::Error> {
+    debug!("Merging track {duplicate} into {keep}");
+    tx.execute(
+        "UPDATE tracks SET playcount = playcount + (
+            SELECT playcount FROM tracks WHERE id = ?2
+        ) WHERE id = ?1",
+        [keep, duplicate],
+    )?;
+    tx.execute(
+        "UPDATE history SET songid = ?1 WHERE songid = ?2",
+        [keep, duplicate],
+    )?;
+    tx.execute("DELETE FROM tracks WHERE id = ?1", [duplicate])?;
+    Ok(())
+}
@@ -0,0 +1,403 @@
+use colored::Colorize;
+use itertools::Itertools;
+use log::{debug, error};
+use rusqlite::{Connection, OptionalExtension};
+use std::collections::HashMap;
+use tabled::{builder::Builder, settings::Style};
+
+use crate::mpd_client::{MPDClient, escape, filter_value};
+use crate::stats::{
+    IN_WINDOW, RankedItem, StatsReport, TimeWindow, build_report, colored_ranking, format_duration,
+    logged,
+};
+
+/// A year of listening, built entirely from `history`.
+pub(crate) struct WrappedReport {
+    year: i32,
+    stats: StatsReport,
+    busiest_day: Option<Busiest>,
+    busiest_month: Option<Busiest>,
+    longest_streak: Option<Streak>,
+    new_artists: Vec<String>,
+    full_albums: Vec<RankedItem>,
+}
+
+/// The day (or month) with the most listening time.
+struct Busiest {
+    label: String,
+    listens: i64,
+    seconds: u64,
+}
+
+/// A run of consecutive days with at least one play.
+struct Streak {
+    start: String,
+    end: String,
+    days: i64,
+}
+
+pub(crate) fn current_year(db: &Connection) -> Result<i32, rusqlite::Error> {
+    db.query_row("select cast(strftime('%Y', 'now') as integer)", [], |row| {
+        row.get(0)
+    })
+}
+
+fn busiest(
+    db: &Connection,
+    window: &TimeWindow,
+    format: &str,
+) -> Result<Option<Busiest>, rusqlite::Error> {
+    let query = format!(
+        "select strftime('{format}', history.time) as period, count(*), sum(lengthseconds) as s
+        from tracks inner join history on tracks.id = history.songid
+        where history.event = 'play' and {IN_WINDOW} group by period order by s desc limit 1"
+    );
+    db.query_row(&query, [&window.since, &window.until], |row| {
+        Ok(Busiest {
+            label: row.get(0)?,
+            listens: row.get(1)?,
+            seconds: row.get::<_, f64>(2)? as u64,
+        })
+    })
+    .optional()
+}
+
+fn longest_streak(db: &Connection, window: &TimeWindow) -> Result<Option<Streak>, rusqlite::Error> {
+    // NOTE: the classic gaps and islands trick, consecutive days minus their row number all land
+    // on the same value
+    let query = format!(
+        "with days as (
+            select distinct date(history.time) as d from history where history.event = 'play' and {IN_WINDOW}
+        ), islands as (
+            select d, julianday(d) - row_number() over (order by d) as grp from days
+        )
+        select min(d), max(d), count(*) as n from islands group by grp order by n desc, min(d) limit 1"
+    );
+    db.query_row(&query, [&window.since, &window.until], |row| {
+        Ok(Streak {
+            start: row.get(0)?,
+            end: row.get(1)?,
+            days: row.get(2)?,
+        })
+    })
+    .optional()
+}
+
+/// Artists whose first ever play falls inside the window.
+fn new_artists(db: &Connection, window: &TimeWindow) -> Result<Vec<String>, rusqlite::Error> {
+    let query = "select artist, min(history.time) as first
+        from tracks inner join history on tracks.id = history.songid
+        where history.event = 'play' group by artist
+        having first >= coalesce(?1, '') and first < coalesce(?2, '9999-12-31')
+        order by first";
+    Ok(db
+        .prepare(query)?
+        .query_map([&window.since, &window.until], |row| {
+            Ok(row
+                .get::<_, Option<String>>(0)?
+                .unwrap_or("Unknown Artist".to_string()))
+        })?
+        .flatten()
+        .collect())
+}
+
+/// Albums heard start to finish: an unbroken run of plays from one album covering at least as many
+/// distinct tracks as MPD has for it.
+fn full_albums(
+    db: &Connection,
+    client: &mut MPDClient,
+    window: &TimeWindow,
+) -> Result<Vec<RankedItem>, rusqlite::Error> {
+    let query = format!(
+        "select artist, album, tracks.id from tracks inner join history on tracks.id = history.songid
+        where history.event = 'play' and {IN_WINDOW} and album != 'Unknown Album'
+        order by history.time"
+    );
+    let plays: Vec<(String, String, i64)> = db
+        .prepare(&query)?
+        .query_map([&window.since, &window.until], |row| {
+            Ok((
+                row.get::<_, Option<String>>(0)?.unwrap_or_default(),
+                row.get::<_, Option<String>>(1)?.unwrap_or_default(),
+                row.get(2)?,
+            ))
+        })?
+        .flatten()
+        .collect();
+
+    let runs = plays
+        .iter()
+        .chunk_by(|(artist, album, _)| (artist.clone(), album.clone()));
+    let mut album_sizes = HashMap::<(String, String), Option<usize>>::new();
+    let mut full_listens = HashMap::<(String, String), i64>::new();
+    for (key, run) in &runs {
+        let heard = run.map(|(_, _, id)| id).unique().count();
+        // a lone track is never an album listen, and skipping it saves a round trip to MPD
+        if heard < 2 {
+            continue;
+        }
+        let size = *album_sizes
+            .entry(key.clone())
+            .or_insert_with(|| album_size(client, &key.0, &key.1));
+        if size.is_some_and(|size| heard >= size) {
+            *full_listens.entry(key).or_default() += 1;
+        }
+    }
+
+    Ok(full_listens
+        .into_iter()
+        .sorted_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)))
+        .map(|((artist, album), count)| RankedItem {
+            artist,
+            album: Some(album),
+            title: None,
+            count,
+        })
+        .collect())
+}
+
+fn album_size(client: &mut MPDClient, artist: &str, album: &str) -> Option<usize> {
+    let filter = format!(
+        "((Artist == {}) AND (Album == {}))",
+        filter_value(artist),
+        filter_value(album)
+    );
+    match client.send_command(format!("count \"{}\"\n", escape(&filter))) {
+        Ok(count) => {
+            let size = count.get("songs").and_then(|s| s.parse().ok());
+            debug!("{artist} - {album} has {size:?} tracks");
+            size
+        }
+        Err(err) => {
+            error!("Could not count tracks for {artist} - {album}: {err}");
+            None
+        }
+    }
+}
+
+pub(crate) fn build_wrapped(
+    db: &Connection,
+    client: &mut MPDClient,
+    year: i32,
+    limit: u32,
+) -> WrappedReport {
+    let window = TimeWindow::year(year);
+    WrappedReport {
+        year,
+        stats: build_report(db, &window, limit),
+        busiest_day: logged("busiest day", busiest(db, &window, "%Y-%m-%d")).flatten(),
+        busiest_month: logged("busiest month", busiest(db, &window, "%Y-%m")).flatten(),
+        longest_streak: logged("longest streak", longest_streak(db, &window)).flatten(),
+        new_artists: logged("new artists", new_artists(db, &window)).unwrap_or_default(),
+        full_albums: logged("full album listens", full_albums(db, client, &window))
+            .unwrap_or_default(),
+    }
+}
+
+fn hours(seconds: Option<u64>) -> String {
+    seconds
+        .map(|s| format!("{:.1} hours", s as f64 / 3600.0))
+        .unwrap_or("Unknown".to_string())
+}
+
+fn describe_busiest(busiest: &Option<Busiest>) -> String {
+    busiest
+        .as_ref()
+        .map(|b| {
+            format!(
+                "{} ({} listens, {})",
+                b.label,
+                b.listens,
+                format_duration(b.seconds)
+            )
+        })
+        .unwrap_or("None".to_string())
+}
+
+fn describe_streak(streak: &Option<Streak>) -> String {
+    streak
+        .as_ref()
+        .map(|s| format!("{} days ({} to {})", s.days, s.start, s.end))
+        .unwrap_or("None".to_string())
+}
+
+pub(crate) fn wrapped_table(report: &WrappedReport) -> String {
+    let mut table_builder = Builder::with_capacity(11, 2);
+    [
+        vec![
+            "Eurydice Wrapped".italic().to_string(),
+            report.year.to_string().bold().magenta().to_string(),
+        ],
+        vec![
+            "Listening Time".italic().to_string(),
+            hours(report.stats.total_playtime_seconds)
+                .bold()
+                .green()
+                .to_string(),
+        ],
+        vec![
+            "Track Listens".italic().to_string(),
+            report
+                .stats
+                .total_listens
+                .map(|c| c.to_string())
+                .unwrap_or("Unknown".to_string())
+                .bold()
+                .green()
+                .to_string(),
+        ],
+        vec![
+            "Top Artists".italic().to_string(),
+            colored_ranking(&report.stats.top_artists),
+        ],
+        vec![
+            "Top Albums".italic().to_string(),
+            colored_ranking(&report.stats.top_albums),
+        ],
+        vec![
+            "Top Tracks".italic().to_string(),
+            colored_ranking(&report.stats.top_tracks),
+        ],
+        vec![
+            "Busiest Day".italic().to_string(),
+            describe_busiest(&report.busiest_day).bold().to_string(),
+        ],
+        vec![
+            "Busiest Month".italic().to_string(),
+            describe_busiest(&report.busiest_month).bold().to_string(),
+        ],
+        vec![
+            "Longest Streak".italic().to_string(),
+            describe_streak(&report.longest_streak).bold().to_string(),
+        ],
+        vec![
+            format!("New Artists ({})", report.new_artists.len())
+                .italic()
+                .to_string(),
+            report
+                .new_artists
+                .iter()
+                .take(5)
+                .map(|a| a.italic().red().to_string())
+                .join("\n"),
+        ],
+        vec![
+            "Albums Heard Start To Finish".italic().to_string(),
+            colored_ranking(&report.full_albums[..report.full_albums.len().min(5)]),
+        ],
+    ]
+    .iter()
+    .for_each(|r| table_builder.push_record(r));
+    let mut table = table_builder.build();
+    table.with(Style::modern_rounded()).to_string()
+}
+
+fn html_escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
+
+fn html_ranking(items: &[RankedItem]) -> String {
+    if items.is_empty() {
+        return "<p class=\"empty\">Nothing yet</p>".to_string();
+    }
+    "<ol>".to_string()
+        + &items
+            .iter()
+            .map(|item| {
+                let name = [Some(&item.artist), item.album.as_ref(), item.title.as_ref()]
+                    .into_iter()
+                    .flatten()
+                    .map(|n| html_escape(n))
+                    .join(" &middot; ");
+                format!(
+                    "<li>{name} <span class=\"count\">{}</span></li>",
+                    item.count
+                )
+            })
+            .join("")
+        + "</ol>"
+}
+
+fn html_card(title: &str, body: &str) -> String {
+    format!("<section><h2>{}</h2>{body}</section>", html_escape(title))
+}
+
+/// A single self-contained HTML page (no external assets) for sharing or keeping.
+pub(crate) fn wrapped_html(report: &WrappedReport) -> String {
+    let big = |text: &str| format!("<p class=\"big\">{}</p>", html_escape(text));
+    let cards = [
+        html_card(
+            "Listening Time",
+            &big(&hours(report.stats.total_playtime_seconds)),
+        ),
+        html_card(
+            "Track Listens",
+            &big(&report
+                .stats
+                .total_listens
+                .map(|c| c.to_string())
+                .unwrap_or("Unknown".to_string())),
+        ),
+        html_card("Top Artists", &html_ranking(&report.stats.top_artists)),
+        html_card("Top Albums", &html_ranking(&report.stats.top_albums)),
+        html_card("Top Tracks", &html_ranking(&report.stats.top_tracks)),
+        html_card("Busiest Day", &big(&describe_busiest(&report.busiest_day))),
+        html_card(
+            "Busiest Month",
+            &big(&describe_busiest(&report.busiest_month)),
+        ),
+        html_card(
+            "Longest Streak",
+            &big(&describe_streak(&report.longest_streak)),
+        ),
+        html_card(
+            &format!("New Artists ({})", report.new_artists.len()),
+            &("<ul>".to_string()
+                + &report
+                    .new_artists
+                    .iter()
+                    .map(|a| format!("<li>{}</li>", html_escape(a)))
+                    .join("")
+                + "</ul>"),
+        ),
+        html_card(
+            "Albums Heard Start To Finish",
+            &html_ranking(&report.full_albums),
+        ),
+    ]
+    .join("\n");
+
+    format!(
+        r#"<!DOCTYPE html>
+<html lang="en">
+<head>
+<meta charset="utf-8">
+<meta name="viewport" content="width=device-width, initial-scale=1">
+<title>Eurydice Wrapped {year}</title>
+<style>
+body {{ background: #14101f; color: #eee; font-family: sans-serif; margin: 0; padding: 2rem; }}
+h1 {{ text-align: center; color: #c792ea; font-size: 3rem; }}
+main {{ display: grid; grid-template-columns: repeat(auto-fit, minmax(18rem, 1fr)); gap: 1rem; }}
+section {{ background: #221a33; border-radius: 1rem; padding: 1rem 1.5rem; }}
+h2 {{ color: #82aaff; font-size: 1.1rem; text-transform: uppercase; letter-spacing: 0.05em; }}
+.big {{ font-size: 1.6rem; font-weight: bold; color: #c3e88d; }}
+.count {{ color: #c3e88d; font-weight: bold; }}
+.empty {{ color: #888; }}
+li {{ margin: 0.3rem 0; }}
+</style>
+</head>
+<body>
+<h1>Eurydice Wrapped {year}</h1>
+<main>
+{cards}
+</main>
+</body>
+</html>
+"#,
+        year = report.year
+    )
+}